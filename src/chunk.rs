@@ -1,50 +1,134 @@
 use std::fmt;
+use std::rc::Rc;
 
+use crate::token::Span;
 use crate::value::Value;
 
-#[derive(Debug)]
+// the names follow clox's `OP_` opcodes
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OpCode {
     // the usize here is the index
     OpConstant(usize),
+    OpNil,
+    OpTrue,
+    OpFalse,
+    OpPop,
+    // the usize here is the stack slot of the local
+    OpGetLocal(usize),
+    OpSetLocal(usize),
+    // the usize here is the index of the constant holding the variable's name
+    OpDefineGlobal(usize),
+    OpGetGlobal(usize),
+    OpSetGlobal(usize),
+    // the usize here is the index into the current closure's upvalues
+    OpGetUpvalue(usize),
+    OpSetUpvalue(usize),
+    OpEqual,
+    OpGreater,
+    OpLess,
     OpAdd,
     OpSubtract,
     OpMultiply,
     OpDivide,
+    OpNot,
     OpNegate,
+    // turn the value on top of the stack into a string, the way `print` would show it
+    OpToString,
+    OpPrint,
+    // jump offsets count instructions from the one following the jump, and are limited to a
+    // `u16` so that a chunk could be serialized the same way as clox's bytecode
+    OpJump(u16),
+    OpJumpIfFalse(u16),
+    OpLoop(u16),
+    // the usize here is the number of arguments
+    OpCall(usize),
+    // the usize here is the index of the constant holding the function to wrap. What the closure
+    // needs to capture is described by the function's `upvalues`.
+    OpClosure(usize),
+    // move the local on top of the stack into the upvalue that captured it
+    OpCloseUpvalue,
+    // the usize here is the index of the constant holding the class's name
+    OpClass(usize),
+    // the usize here is the index of the constant holding the property's name
+    OpGetProperty(usize),
+    OpSetProperty(usize),
+    // add the closure on top of the stack to the class just below it, under the name held in
+    // the constant at this index
+    OpMethod(usize),
+    // a method call straight off an instance, like `a.b(c)`, without creating a bound method in
+    // between. Holds the index of the method name constant and the number of arguments.
+    OpInvoke(usize, usize),
+    // copy the methods of the superclass just below the top of the stack down into the class on
+    // top of it
+    OpInherit,
+    // like `OpGetProperty` and `OpInvoke`, but the method is looked up on the superclass on top
+    // of the stack, rather than the class of the instance below it
+    OpGetSuper(usize),
+    OpSuperInvoke(usize, usize),
     OpReturn,
 }
 
 impl fmt::Display for OpCode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            OpCode::OpPrint => f.pad("OP_PRINT"),
+            OpCode::OpJump(offset) => f.pad(&format!("OP_JUMP: {}", offset)),
+            OpCode::OpJumpIfFalse(offset) => f.pad(&format!("OP_JUMP_IF_FALSE: {}", offset)),
+            OpCode::OpLoop(offset) => f.pad(&format!("OP_LOOP: {}", offset)),
+            OpCode::OpCall(arg_count) => f.pad(&format!("OP_CALL: {}", arg_count)),
+            OpCode::OpClosure(index) => f.pad(&format!("OP_CLOSURE: {}", index)),
+            OpCode::OpCloseUpvalue => f.pad("OP_CLOSE_UPVALUE"),
+            OpCode::OpClass(index) => f.pad(&format!("OP_CLASS: {}", index)),
+            OpCode::OpGetProperty(index) => f.pad(&format!("OP_GET_PROPERTY: {}", index)),
+            OpCode::OpSetProperty(index) => f.pad(&format!("OP_SET_PROPERTY: {}", index)),
+            OpCode::OpMethod(index) => f.pad(&format!("OP_METHOD: {}", index)),
+            OpCode::OpInvoke(index, arg_count) => {
+                f.pad(&format!("OP_INVOKE: ({} args) {}", arg_count, index))
+            }
+            OpCode::OpInherit => f.pad("OP_INHERIT"),
+            OpCode::OpGetSuper(index) => f.pad(&format!("OP_GET_SUPER: {}", index)),
+            OpCode::OpSuperInvoke(index, arg_count) => {
+                f.pad(&format!("OP_SUPER_INVOKE: ({} args) {}", arg_count, index))
+            }
             OpCode::OpReturn => f.pad("OP_RETURN"),
+            OpCode::OpNil => f.pad("OP_NIL"),
+            OpCode::OpTrue => f.pad("OP_TRUE"),
+            OpCode::OpFalse => f.pad("OP_FALSE"),
+            OpCode::OpPop => f.pad("OP_POP"),
+            OpCode::OpGetLocal(slot) => f.pad(&format!("OP_GET_LOCAL: {}", slot)),
+            OpCode::OpSetLocal(slot) => f.pad(&format!("OP_SET_LOCAL: {}", slot)),
+            OpCode::OpDefineGlobal(index) => f.pad(&format!("OP_DEFINE_GLOBAL: {}", index)),
+            OpCode::OpGetGlobal(index) => f.pad(&format!("OP_GET_GLOBAL: {}", index)),
+            OpCode::OpSetGlobal(index) => f.pad(&format!("OP_SET_GLOBAL: {}", index)),
+            OpCode::OpGetUpvalue(index) => f.pad(&format!("OP_GET_UPVALUE: {}", index)),
+            OpCode::OpSetUpvalue(index) => f.pad(&format!("OP_SET_UPVALUE: {}", index)),
+            OpCode::OpEqual => f.pad("OP_EQUAL"),
+            OpCode::OpGreater => f.pad("OP_GREATER"),
+            OpCode::OpLess => f.pad("OP_LESS"),
             OpCode::OpAdd => f.pad("OP_ADD"),
             OpCode::OpSubtract => f.pad("OP_SUBTRACT"),
             OpCode::OpMultiply => f.pad("OP_MULTIPLY"),
             OpCode::OpDivide => f.pad("OP_DIVIDE"),
+            OpCode::OpNot => f.pad("OP_NOT"),
             OpCode::OpNegate => f.pad("OP_NEGATE"),
+            OpCode::OpToString => f.pad("OP_TO_STRING"),
             OpCode::OpConstant(value) => f.pad(&format!("OP_CONSTANT: {}", value)),
         }
     }
 }
 
-#[derive(Debug)]
-pub struct OpcodeLine {
-    // the index within the associated chunk of the first instruction on the line
-    start: usize,
-    // the index within the associated chunk of the last instruction on the line
-    end: usize,
-    line_number: i32,
-}
-
 /// A Chunk represents a unit of compiled bytecode which can then be interpreted by the virtual
 /// machine
 #[derive(Debug)]
 pub struct Chunk {
     pub code: Vec<OpCode>,
     pub constants: Vec<Value>,
-    pub lines: Vec<OpcodeLine>,
-    current_line: Option<i32>,
+    // where in the source each instruction came from, for pointing at it in error messages and
+    // for the line numbers in the disassembly
+    pub spans: Vec<Span>,
+    // the source code `spans` point into
+    pub source: Rc<str>,
 }
 
 impl Chunk {
@@ -52,29 +136,14 @@ impl Chunk {
         Chunk {
             code: vec![],
             constants: vec![],
-            lines: vec![],
-            current_line: None,
+            spans: vec![],
+            source: Rc::from(""),
         }
     }
 
-    pub fn write(&mut self, byte: OpCode, line_number: i32) {
+    pub fn write(&mut self, byte: OpCode, span: Span) {
         self.code.push(byte);
-        self.parse_line(line_number);
-    }
-
-    pub fn parse_line(&mut self, line_number: i32) {
-        if let Some(current_line_number) = self.current_line {
-            if current_line_number != line_number {
-                self.current_line = Some(line_number);
-                self.lines.push(OpcodeLine {
-                    start: self.lines.last().map(|line| line.start).unwrap_or(0),
-                    end: self.code.len() - 1,
-                    line_number,
-                })
-            }
-        } else {
-            self.current_line = Some(line_number);
-        }
+        self.spans.push(span);
     }
 
     pub fn add_constant(&mut self, value: Value) -> usize {
@@ -86,22 +155,9 @@ impl Chunk {
         self.constants[index]
     }
 
-    fn get_line_number(&self, index: usize) -> Option<i32> {
-        self.lines
-            .iter()
-            .find(|line| line.start <= index && index <= line.end)
-            .map(|line| line.line_number)
-    }
-
-    pub fn end_line_parsing(&mut self) {
-        if let Some(current_line) = self.current_line {
-            self.lines.push(OpcodeLine {
-                start: self.lines.last().map(|line| line.start).unwrap_or(0),
-                end: self.code.len() - 1,
-                line_number: current_line,
-            });
-            self.current_line = None;
-        }
+    /// Find the part of the source the instruction at `index` was compiled from
+    pub fn get_span(&self, index: usize) -> Option<Span> {
+        self.spans.get(index).copied()
     }
 
     pub fn disassemble(&self, name: &str) {
@@ -116,30 +172,92 @@ impl Chunk {
 
     pub fn disassemble_instruction(&self, i: usize, opcode: &OpCode) {
         match opcode {
-            OpCode::OpConstant(index) => {
-                println!(
-                    "{:0>4} {} {} '{}'",
-                    i,
-                    self.get_line_number(i).unwrap_or(-1),
-                    OpCode::OpConstant(*index),
-                    self.constants[*index]
-                );
-            }
+            OpCode::OpConstant(index) => self.print_constant_instruction(i, opcode, *index),
+            OpCode::OpDefineGlobal(index) => self.print_constant_instruction(i, opcode, *index),
+            OpCode::OpGetGlobal(index) => self.print_constant_instruction(i, opcode, *index),
+            OpCode::OpSetGlobal(index) => self.print_constant_instruction(i, opcode, *index),
+            OpCode::OpPop => self.print_simple_instruction(i, opcode),
+            OpCode::OpGetUpvalue(_) => self.print_simple_instruction(i, opcode),
+            OpCode::OpSetUpvalue(_) => self.print_simple_instruction(i, opcode),
+            OpCode::OpGetLocal(_) => self.print_simple_instruction(i, opcode),
+            OpCode::OpSetLocal(_) => self.print_simple_instruction(i, opcode),
+            OpCode::OpNil => self.print_simple_instruction(i, opcode),
+            OpCode::OpTrue => self.print_simple_instruction(i, opcode),
+            OpCode::OpFalse => self.print_simple_instruction(i, opcode),
+            OpCode::OpEqual => self.print_simple_instruction(i, opcode),
+            OpCode::OpGreater => self.print_simple_instruction(i, opcode),
+            OpCode::OpLess => self.print_simple_instruction(i, opcode),
             OpCode::OpAdd => self.print_simple_instruction(i, opcode),
             OpCode::OpSubtract => self.print_simple_instruction(i, opcode),
             OpCode::OpMultiply => self.print_simple_instruction(i, opcode),
             OpCode::OpDivide => self.print_simple_instruction(i, opcode),
+            OpCode::OpNot => self.print_simple_instruction(i, opcode),
             OpCode::OpNegate => self.print_simple_instruction(i, opcode),
+            OpCode::OpToString => self.print_simple_instruction(i, opcode),
+            OpCode::OpPrint => self.print_simple_instruction(i, opcode),
+            OpCode::OpJump(offset) => self.print_jump_instruction(i, opcode, *offset as isize),
+            OpCode::OpJumpIfFalse(offset) => {
+                self.print_jump_instruction(i, opcode, *offset as isize)
+            }
+            OpCode::OpLoop(offset) => self.print_jump_instruction(i, opcode, -(*offset as isize)),
+            OpCode::OpCall(_) => self.print_simple_instruction(i, opcode),
+            OpCode::OpClosure(index) => self.print_closure_instruction(i, opcode, *index),
+            OpCode::OpCloseUpvalue => self.print_simple_instruction(i, opcode),
+            OpCode::OpClass(index) => self.print_constant_instruction(i, opcode, *index),
+            OpCode::OpGetProperty(index) => self.print_constant_instruction(i, opcode, *index),
+            OpCode::OpSetProperty(index) => self.print_constant_instruction(i, opcode, *index),
+            OpCode::OpMethod(index) => self.print_constant_instruction(i, opcode, *index),
+            OpCode::OpInvoke(index, _) => self.print_constant_instruction(i, opcode, *index),
+            OpCode::OpInherit => self.print_simple_instruction(i, opcode),
+            OpCode::OpGetSuper(index) => self.print_constant_instruction(i, opcode, *index),
+            OpCode::OpSuperInvoke(index, _) => self.print_constant_instruction(i, opcode, *index),
             OpCode::OpReturn => self.print_simple_instruction(i, opcode),
         }
     }
 
     // private functions
+    fn print_constant_instruction(&self, index: usize, opcode: &OpCode, constant: usize) {
+        println!(
+            "{:0>4} {} {} '{}'",
+            index,
+            self.get_span(index).map_or(-1, |span| span.line),
+            opcode,
+            self.constants[constant]
+        );
+    }
+
+    fn print_closure_instruction(&self, index: usize, opcode: &OpCode, constant: usize) {
+        self.print_constant_instruction(index, opcode, constant);
+
+        // followed by a line for each variable the closure captures
+        if let Value::Obj(obj) = self.constants[constant] {
+            if let Some(function) = obj.as_function() {
+                for upvalue in &function.upvalues {
+                    println!(
+                        "   |          {} {}",
+                        if upvalue.is_local { "local" } else { "upvalue" },
+                        upvalue.index
+                    );
+                }
+            }
+        }
+    }
+
+    fn print_jump_instruction(&self, index: usize, opcode: &OpCode, offset: isize) {
+        println!(
+            "{:0>4} {} {} -> {}",
+            index,
+            self.get_span(index).map_or(-1, |span| span.line),
+            opcode,
+            index as isize + 1 + offset
+        );
+    }
+
     fn print_simple_instruction(&self, index: usize, opcode: &OpCode) {
         println!(
             "{:0>4} {} {} ",
             index,
-            self.get_line_number(index).unwrap_or(-1),
+            self.get_span(index).map_or(-1, |span| span.line),
             opcode
         );
     }
@@ -1,13 +1,66 @@
-// use crate::token::TokenInfo;
 use crate::chunk::Chunk;
 use crate::chunk::OpCode;
+use crate::memory::{Heap, Roots};
+use crate::object::{ObjFunction, ObjKind, ObjRef, UpvalueDescriptor};
 use crate::scanner::Scanner;
 use crate::token::Token;
-use crate::token::TokenInfo;
+use crate::token::{Span, TokenInfo};
 use crate::value::Value;
 use log::{error, log_enabled, Level};
+use std::fmt;
+use std::rc::Rc;
 
-pub fn compile(source: &str) -> anyhow::Result<Chunk> {
+/// A problem found while compiling, pointing at the token where it was noticed
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub span: Span,
+    // the source text of the offending token, which is empty at the end of the file. Errors from
+    // the scanner don't have one, since there's no token to point at.
+    pub lexeme: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.lexeme.as_deref() {
+            None => write!(f, "[line {}] Error: {}", self.span.line, self.message),
+            Some("") => write!(
+                f,
+                "[line {}] Error at end: {}",
+                self.span.line, self.message
+            ),
+            Some(lexeme) => write!(
+                f,
+                "[line {}] Error at '{}': {}",
+                self.span.line, lexeme, self.message
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Like `compile_with_roots`, for a heap that nothing else is holding onto objects from
+#[cfg(test)]
+pub(crate) fn compile(source: &str, heap: &mut Heap) -> Result<ObjRef, Vec<CompileError>> {
+    compile_with_roots(source, heap, &())
+}
+
+/// Compile `source` into a function wrapping the whole script. The function, and any objects its
+/// bytecode needs (string constants, nested functions), are allocated on `heap`. The garbage
+/// collector may run while compiling, and keeps everything in `roots` alive.
+///
+/// The script returns the value of its last statement if that's an expression statement, and
+/// `nil` otherwise.
+///
+/// If there are any errors, every one of them is returned rather than just the first. After an
+/// error the compiler skips ahead to the next statement, so errors which follow on from an earlier
+/// one aren't reported.
+pub(crate) fn compile_with_roots(
+    source: &str,
+    heap: &mut Heap,
+    roots: &dyn Roots,
+) -> Result<ObjRef, Vec<CompileError>> {
     if log_enabled!(Level::Debug) {
         // do the debug scan
         // we do this with a separate throwaway scanner instance
@@ -16,138 +69,2065 @@ pub fn compile(source: &str) -> anyhow::Result<Chunk> {
     }
 
     let scanner = Scanner::init(source);
-    let parser = Parser::init(scanner);
+    let mut parser = Parser::init(scanner, heap, roots);
     parser.advance();
 
-    Ok(Chunk::init())
+    while !parser.matches(Token::EOF) {
+        let is_expression_statement = parser.at_expression_statement();
+        parser.declaration();
+
+        // the script returns the value of an expression statement at its very end
+        if is_expression_statement && parser.check(&Token::EOF) {
+            parser.return_last_value();
+        }
+    }
+
+    parser.finish()
+}
+
+/// The most local variables that can be in scope at once. Locals live in stack slots, and we cap
+/// them at the same 256 that a single byte operand could address in clox.
+pub const MAX_LOCALS: usize = 256;
+
+/// The most parameters a function can have, and so the most arguments a call can pass
+pub const MAX_ARGUMENTS: usize = 255;
+
+/// The most variables a single closure can capture
+pub const MAX_UPVALUES: usize = 256;
+
+/// A local variable which has been declared in the code being compiled
+struct Local {
+    name: String,
+    // how many blocks deep the variable was declared, or `None` while its initializer is still
+    // being compiled
+    depth: Option<usize>,
+    // whether a closure has captured this variable, in which case it has to be moved off the
+    // stack when it goes out of scope
+    is_captured: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionType {
+    Function,
+    // a method called `init`, which always returns the instance it was called on
+    Initializer,
+    Method,
+    Script,
+}
+
+/// Keeps track of the function currently being compiled, along with its lexical scopes and local
+/// variables. The order of `locals` mirrors the order the VM pushes them onto its stack, so the
+/// index of a `Local` is also its stack slot (relative to the start of the function's frame).
+///
+/// Function declarations can be nested, so each `Compiler` points back to the one for the
+/// function it's nested in.
+struct Compiler {
+    enclosing: Option<Box<Compiler>>,
+    function: ObjFunction,
+    function_type: FunctionType,
+    locals: Vec<Local>,
+    scope_depth: usize,
+}
+
+impl Compiler {
+    fn init(function_type: FunctionType, name: Option<ObjRef>) -> Compiler {
+        Compiler {
+            enclosing: None,
+            function: ObjFunction::init(name),
+            function_type,
+            // The VM puts the function being called in the first stack slot of its frame, so we
+            // claim that slot with a local that can never be referenced by name. For methods,
+            // that slot holds the instance instead, which is exactly what `this` refers to.
+            locals: vec![Local {
+                name: match function_type {
+                    FunctionType::Initializer | FunctionType::Method => String::from("this"),
+                    FunctionType::Function | FunctionType::Script => String::new(),
+                },
+                depth: Some(0),
+                is_captured: false,
+            }],
+            scope_depth: 0,
+        }
+    }
+
+    /// Find the stack slot of the innermost local variable with the given name. Errors are
+    /// returned as a message for the parser to report.
+    fn resolve_local(&self, name: &str) -> Result<Option<usize>, &'static str> {
+        let found = self
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name);
+
+        match found {
+            Some((_, local)) if local.depth.is_none() => {
+                Err("Can't read local variable in its own initializer.")
+            }
+            Some((slot, _)) => Ok(Some(slot)),
+            None => Ok(None),
+        }
+    }
+
+    /// Find a variable with the given name declared in one of the enclosing functions, and make
+    /// sure that every function in between captures it. Returns the index of the upvalue in this
+    /// function.
+    fn resolve_upvalue(&mut self, name: &str) -> Result<Option<usize>, &'static str> {
+        let enclosing = match self.enclosing.as_deref_mut() {
+            Some(enclosing) => enclosing,
+            None => return Ok(None),
+        };
+
+        if let Some(slot) = enclosing.resolve_local(name)? {
+            enclosing.locals[slot].is_captured = true;
+            return self.add_upvalue(slot, true).map(Some);
+        }
+
+        if let Some(index) = enclosing.resolve_upvalue(name)? {
+            return self.add_upvalue(index, false).map(Some);
+        }
+
+        Ok(None)
+    }
+
+    fn add_upvalue(&mut self, index: usize, is_local: bool) -> Result<usize, &'static str> {
+        let upvalue = UpvalueDescriptor { index, is_local };
+        let upvalues = &mut self.function.upvalues;
+
+        // a closure only needs to capture each variable once, no matter how often it's used
+        if let Some(existing) = upvalues.iter().position(|u| *u == upvalue) {
+            return Ok(existing);
+        }
+
+        if upvalues.len() >= MAX_UPVALUES {
+            return Err("Too many closure variables in function.");
+        }
+
+        upvalues.push(upvalue);
+        Ok(upvalues.len() - 1)
+    }
+
+    /// The functions still being compiled aren't on the heap yet, but the objects they refer to
+    /// are, so they have to be kept alive
+    fn mark_roots(&self, heap: &mut Heap) {
+        if let Some(name) = self.function.name {
+            heap.mark_object(name);
+        }
+        for constant in &self.function.chunk.constants {
+            heap.mark_value(*constant);
+        }
+
+        if let Some(enclosing) = &self.enclosing {
+            enclosing.mark_roots(heap);
+        }
+    }
+}
+
+/// Keeps track of the class whose body is currently being compiled. Classes can be nested (e.g.
+/// inside a method), so the parser keeps a stack of these.
+struct ClassCompiler {
+    has_superclass: bool,
 }
 
 pub struct Parser<'a> {
-    current: Option<TokenInfo>,
-    previous: Option<TokenInfo>,
+    current: Option<TokenInfo<'a>>,
+    previous: Option<TokenInfo<'a>>,
     scanner: Scanner<'a>,
-    had_error: bool,
+    errors: Vec<CompileError>,
     panicking: bool,
-    chunk: Chunk,
+    heap: &'a mut Heap,
+    // whatever else is using `heap`, which has to survive any collections while we compile
+    roots: &'a dyn Roots,
+    compiler: Compiler,
+    classes: Vec<ClassCompiler>,
+    // a copy of the source for every chunk to keep, so that errors from a function can still be
+    // pointed out after the code it was defined in is gone
+    source: Rc<str>,
 }
 
-impl Parser<'_> {
-    pub fn init(scanner: Scanner) -> Parser {
+impl<'a> Parser<'a> {
+    pub(crate) fn init(
+        scanner: Scanner<'a>,
+        heap: &'a mut Heap,
+        roots: &'a dyn Roots,
+    ) -> Parser<'a> {
         Parser {
+            source: Rc::from(scanner.source()),
             current: None,
             previous: None,
-            had_error: false,
+            errors: vec![],
             panicking: false,
             scanner,
-            chunk: Chunk::init(),
+            heap,
+            roots,
+            compiler: Compiler::init(FunctionType::Script, None),
+            classes: vec![],
         }
     }
 
-    pub fn advance(mut self) {
-        self.previous = self.current;
+    pub fn advance(&mut self) {
+        self.previous = self.current.take();
 
+        // the scanner leaves out comments and whitespace, which don't mean anything to the compiler
         loop {
-            self.current = Some(self.scanner.scan_token());
-
-            let token_info = self.scanner.scan_token();
-
-            // we only report the first error we run into
-            if token_info.token == Token::Error && !self.panicking {
-                // some scanner error is being reported here
-                self.had_error();
-                error_at(&token_info, error_message_for(&token_info));
-                self.current = Some(token_info);
-            } else {
-                self.current = Some(token_info);
+            let Some(token_info) = self.scanner.next() else {
+                // the scanner stops after the end of the file, where we stay
+                self.current = self.previous.clone();
                 break;
+            };
+
+            match token_info.token {
+                Token::Error(ref message) => {
+                    // the scanner couldn't make a token, and has told us why
+                    let message = message.clone();
+                    self.error_at(&token_info, &message);
+                }
+                _ => {
+                    self.current = Some(token_info);
+                    break;
+                }
             }
         }
     }
 
-    pub fn consume(mut self, token_type: Token, message: String) {
-        if self.current.as_ref().is_some_and(|t| t.token == token_type) {
+    pub fn consume(&mut self, token_type: Token, message: &str) {
+        if self.check(&token_type) {
             self.advance();
         } else {
-            self.had_error();
-            // TODO handle None case for `self.current` here
-            self.current.map(|cur| error_at(&cur, message));
+            self.error_at_current(message);
+        }
+    }
+
+    /// Wrap up compilation, handing back the function for the whole script if no errors were
+    /// found along the way.
+    pub fn finish(mut self) -> Result<ObjRef, Vec<CompileError>> {
+        let script = self.end_compiler();
+
+        if self.errors.is_empty() {
+            Ok(script)
+        } else {
+            Err(self.errors)
+        }
+    }
+
+    /// Check whether the current token is of the given type without consuming it
+    fn check(&self, token_type: &Token) -> bool {
+        self.current
+            .as_ref()
+            .is_some_and(|t| t.token == *token_type)
+    }
+
+    /// Consume the current token if it's of the given type, returning whether it was
+    fn matches(&mut self, token_type: Token) -> bool {
+        if !self.check(&token_type) {
+            return false;
         }
+        self.advance();
+        true
     }
 
-    fn emit_byte(mut self, op_code: OpCode) {
-        if let Some(prev) = self.previous {
-            self.chunk.write(op_code, prev.line);
+    /// The chunk belonging to the function we're currently compiling
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.compiler.function.chunk
+    }
+
+    fn emit_byte(&mut self, op_code: OpCode) {
+        if let Some(span) = self.previous.as_ref().map(|prev| prev.span) {
+            self.chunk().write(op_code, span);
         } else {
             error!("expected to find a previously parsed token!");
         }
     }
 
-    fn end_compilation(self) {
+    /// Emit an instruction which belongs to some earlier token than the one we just parsed, e.g.
+    /// the operator of a binary expression, so that errors point at the right place
+    fn emit_byte_at(&mut self, op_code: OpCode, span: Span) {
+        self.chunk().write(op_code, span);
+    }
+
+    /// The heap, after running the garbage collector if it's due. Anything which might allocate
+    /// should go through this, so that collections only happen while all of our objects are
+    /// reachable from a root.
+    fn collecting_heap(&mut self) -> &mut Heap {
+        if self.heap.should_collect() {
+            self.roots.mark_roots(self.heap);
+            self.compiler.mark_roots(self.heap);
+            self.heap.collect();
+        }
+        self.heap
+    }
+
+    /// Start compiling a new function nested inside the current one
+    fn begin_compiler(&mut self, function_type: FunctionType, name: &str) {
+        let name = self.collecting_heap().copy_string(name);
+        let compiler = Compiler::init(function_type, Some(name));
+        let enclosing = std::mem::replace(&mut self.compiler, compiler);
+        self.compiler.enclosing = Some(Box::new(enclosing));
+    }
+
+    /// Finish off the function we're currently compiling, moving it onto the heap and returning
+    /// to the function it was nested in (if any)
+    fn end_compiler(&mut self) -> ObjRef {
         self.emit_return();
+
+        if log_enabled!(Level::Debug) && self.errors.is_empty() {
+            let function = &self.compiler.function;
+            function.chunk.disassemble(&function.to_string());
+        }
+
+        // collect now, while the function's constants are still reachable through `compiler`
+        self.collecting_heap();
+
+        let compiler = match self.compiler.enclosing.take() {
+            Some(enclosing) => std::mem::replace(&mut self.compiler, *enclosing),
+            None => std::mem::replace(
+                &mut self.compiler,
+                Compiler::init(FunctionType::Script, None),
+            ),
+        };
+        let mut function = compiler.function;
+        function.chunk.source = Rc::clone(&self.source);
+        self.heap.allocate(ObjKind::Function(function))
     }
 
-    fn emit_return(self) {
+    fn emit_return(&mut self) {
+        // a function without an explicit `return` returns nil, apart from an initializer, which
+        // returns the new instance sitting in slot 0
+        if self.compiler.function_type == FunctionType::Initializer {
+            self.emit_byte(OpCode::OpGetLocal(0));
+        } else {
+            self.emit_byte(OpCode::OpNil);
+        }
         self.emit_byte(OpCode::OpReturn);
     }
 
-    fn emit_constant(mut self, value: Value) {
-        let index = self.chunk.add_constant(value);
+    fn emit_constant(&mut self, value: Value) {
+        let index = self.chunk().add_constant(value);
         self.emit_byte(OpCode::OpConstant(index));
     }
 
-    fn had_error(&mut self) {
-        self.had_error = true;
+    /// Emit a jump instruction with a placeholder offset, returning its index so that the real
+    /// offset can be filled in with `patch_jump` once we know where it should land
+    fn emit_jump(&mut self, op_code: OpCode) -> usize {
+        self.emit_byte(op_code);
+        self.chunk().code.len() - 1
+    }
+
+    /// Point the jump at `index` to the next instruction we're going to emit
+    fn patch_jump(&mut self, index: usize) {
+        let offset = match u16::try_from(self.chunk().code.len() - index - 1) {
+            Ok(offset) => offset,
+            Err(_) => {
+                self.error("Too much code to jump over.");
+                return;
+            }
+        };
+
+        match &mut self.chunk().code[index] {
+            OpCode::OpJump(jump) | OpCode::OpJumpIfFalse(jump) => *jump = offset,
+            _ => error!("expected to find a jump instruction to patch!"),
+        }
+    }
+
+    /// Emit a jump backwards to the instruction at `loop_start`
+    fn emit_loop(&mut self, loop_start: usize) {
+        // the VM will already have moved past the `OpLoop` itself when it jumps
+        let offset = match u16::try_from(self.chunk().code.len() + 1 - loop_start) {
+            Ok(offset) => offset,
+            Err(_) => {
+                self.error("Loop body too large.");
+                0
+            }
+        };
+        self.emit_byte(OpCode::OpLoop(offset));
+    }
+
+    /// Add a variable's name to the chunk's constant table, returning its index
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        let string = self.collecting_heap().copy_string(name);
+        self.chunk().add_constant(Value::Obj(string))
+    }
+
+    fn error_at(&mut self, token_info: &TokenInfo, message: &str) {
+        // once we're panicking we suppress any further errors until we get to the next statement,
+        // since they're most likely caused by the first one
+        if self.panicking {
+            return;
+        }
         self.panicking = true;
+
+        self.errors.push(CompileError {
+            span: token_info.span,
+            lexeme: match token_info.token {
+                Token::Error(_) => None,
+                _ => Some(String::from(token_info.lexeme)),
+            },
+            message: String::from(message),
+        });
+    }
+
+    /// Get out of panic mode by skipping tokens until we're at what looks like the start of a new
+    /// statement
+    fn synchronize(&mut self) {
+        while !self.check(&Token::EOF) {
+            if self.previous_token() == Token::Semicolon {
+                break;
+            }
+
+            match self.current_token() {
+                Token::Class
+                | Token::Fun
+                | Token::Var
+                | Token::For
+                | Token::If
+                | Token::While
+                | Token::Print
+                | Token::Return => break,
+                _ => self.advance(),
+            }
+        }
+
+        // only now, so that scanner errors in the tokens we skipped stay suppressed
+        self.panicking = false;
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        if let Some(current) = self.current.clone() {
+            self.error_at(&current, message);
+        }
+    }
+
+    fn error(&mut self, message: &str) {
+        if let Some(previous) = self.previous.clone() {
+            self.error_at(&previous, message);
+        }
+    }
+
+    fn previous_token(&self) -> Token {
+        self.previous
+            .as_ref()
+            .map_or(Token::EOF, |prev| prev.token.clone())
+    }
+
+    fn current_token(&self) -> Token {
+        self.current
+            .as_ref()
+            .map_or(Token::EOF, |cur| cur.token.clone())
+    }
+
+    fn declaration(&mut self) {
+        if self.matches(Token::Class) {
+            self.class_declaration();
+        } else if self.matches(Token::Fun) {
+            self.fun_declaration();
+        } else if self.matches(Token::Var) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+
+        if self.panicking {
+            self.synchronize();
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        let name = match self.current_token() {
+            Token::Identifier(name) => name,
+            _ => {
+                self.error_at_current("Expect class name.");
+                return;
+            }
+        };
+        self.advance();
+
+        // unlike `parse_variable`, we always need the name as a constant so the class knows it
+        let name_constant = self.identifier_constant(&name);
+        if self.compiler.scope_depth > 0 {
+            self.declare_local(name.clone());
+        }
+
+        self.emit_byte(OpCode::OpClass(name_constant));
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassCompiler {
+            has_superclass: false,
+        });
+
+        if self.matches(Token::Less) {
+            let superclass = match self.current_token() {
+                Token::Identifier(superclass) => {
+                    self.advance();
+                    superclass
+                }
+                _ => {
+                    self.error_at_current("Expect superclass name.");
+                    String::new()
+                }
+            };
+            self.named_variable(&superclass, false);
+
+            if superclass == name {
+                self.error("A class can't inherit from itself.");
+            }
+
+            // Methods find their superclass through a local called `super`, which they capture
+            // like any other variable. Each class gets its own scope for it, so that sibling
+            // classes in the same scope don't share a superclass.
+            self.begin_scope();
+            self.declare_local(String::from("super"));
+            self.mark_initialized();
+
+            self.named_variable(&name, false);
+            self.emit_byte(OpCode::OpInherit);
+            if let Some(class) = self.classes.last_mut() {
+                class.has_superclass = true;
+            }
+        }
+
+        // put the class back on the stack so that `OpMethod` can find it
+        self.named_variable(&name, false);
+        self.consume(Token::LeftBrace, "Expect '{' before class body.");
+        while !self.check(&Token::RightBrace) && !self.check(&Token::EOF) {
+            self.method();
+        }
+        self.consume(Token::RightBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::OpPop);
+
+        if self.classes.pop().is_some_and(|class| class.has_superclass) {
+            self.end_scope();
+        }
+    }
+
+    fn method(&mut self) {
+        let name = match self.current_token() {
+            Token::Identifier(name) => name,
+            _ => {
+                self.error_at_current("Expect method name.");
+                // skip the token so that we can't get stuck on it
+                self.advance();
+                return;
+            }
+        };
+        self.advance();
+
+        let constant = self.identifier_constant(&name);
+        let function_type = if name == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+        self.emit_byte(OpCode::OpMethod(constant));
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // unlike other variables, a function can refer to itself in its own body, which is what
+        // makes recursion possible
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    /// Compile a function's parameters and body, leaving a closure over it on the stack
+    fn function(&mut self, function_type: FunctionType) {
+        let name = match self.previous_token() {
+            Token::Identifier(name) => name,
+            _ => String::new(),
+        };
+        self.begin_compiler(function_type, &name);
+        // the function's parameters and body all live in this one scope, which we never need to
+        // end because the whole frame is thrown away when the function returns
+        self.begin_scope();
+
+        self.consume(Token::LeftParen, "Expect '(' after function name.");
+        if !self.check(&Token::RightParen) {
+            loop {
+                self.compiler.function.arity += 1;
+                if self.compiler.function.arity > MAX_ARGUMENTS {
+                    self.error_at_current(&format!(
+                        "Can't have more than {} parameters.",
+                        MAX_ARGUMENTS
+                    ));
+                }
+
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.matches(Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(Token::RightParen, "Expect ')' after parameters.");
+        self.consume(Token::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        let function = self.end_compiler();
+        let index = self.chunk().add_constant(Value::Obj(function));
+        self.emit_byte(OpCode::OpClosure(index));
+    }
+
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.matches(Token::Equal) {
+            self.expression();
+        } else {
+            // `var x;` is sugar for `var x = nil;`
+            self.emit_byte(OpCode::OpNil);
+        }
+        self.consume(Token::Semicolon, "Expect ';' after variable declaration.");
+
+        self.define_variable(global);
+    }
+
+    fn statement(&mut self) {
+        if self.matches(Token::Print) {
+            self.print_statement();
+        } else if self.matches(Token::For) {
+            self.for_statement();
+        } else if self.matches(Token::If) {
+            self.if_statement();
+        } else if self.matches(Token::Return) {
+            self.return_statement();
+        } else if self.matches(Token::While) {
+            self.while_statement();
+        } else if self.matches(Token::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(&Token::RightBrace) && !self.check(&Token::EOF) {
+            self.declaration();
+        }
+
+        self.consume(Token::RightBrace, "Expect '}' after block.");
+    }
+
+    fn begin_scope(&mut self) {
+        self.compiler.scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.compiler.scope_depth -= 1;
+
+        // the locals declared in the scope we're leaving are still sitting on the stack
+        while self
+            .compiler
+            .locals
+            .last()
+            .is_some_and(|local| local.depth > Some(self.compiler.scope_depth))
+        {
+            let is_captured = self.compiler.locals.last().is_some_and(|l| l.is_captured);
+            self.emit_byte(if is_captured {
+                OpCode::OpCloseUpvalue
+            } else {
+                OpCode::OpPop
+            });
+            self.compiler.locals.pop();
+        }
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(Token::LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(Token::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::OpJumpIfFalse(0));
+        // the condition is left on the stack by `OpJumpIfFalse`, so each branch has to pop it
+        self.emit_byte(OpCode::OpPop);
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::OpJump(0));
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::OpPop);
+
+        if self.matches(Token::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.chunk().code.len();
+        self.consume(Token::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(Token::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::OpJumpIfFalse(0));
+        self.emit_byte(OpCode::OpPop);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::OpPop);
+    }
+
+    fn for_statement(&mut self) {
+        // any variable declared in the initializer is scoped to the loop
+        self.begin_scope();
+        self.consume(Token::LeftParen, "Expect '(' after 'for'.");
+
+        if self.matches(Token::Semicolon) {
+            // no initializer
+        } else if self.matches(Token::Var) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.chunk().code.len();
+
+        let mut exit_jump = None;
+        if !self.matches(Token::Semicolon) {
+            self.expression();
+            self.consume(Token::Semicolon, "Expect ';' after loop condition.");
+
+            // jump out of the loop if the condition is false
+            exit_jump = Some(self.emit_jump(OpCode::OpJumpIfFalse(0)));
+            self.emit_byte(OpCode::OpPop);
+        }
+
+        if !self.matches(Token::RightParen) {
+            // The increment comes before the body in the source but runs after it, so we jump
+            // over it to the body, then loop back to it at the end of the body.
+            let body_jump = self.emit_jump(OpCode::OpJump(0));
+            let increment_start = self.chunk().code.len();
+
+            self.expression();
+            self.emit_byte(OpCode::OpPop);
+            self.consume(Token::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::OpPop);
+        }
+
+        self.end_scope();
+    }
+
+    fn return_statement(&mut self) {
+        if self.compiler.function_type == FunctionType::Script {
+            self.error("Can't return from top-level code.");
+        }
+
+        if self.matches(Token::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.compiler.function_type == FunctionType::Initializer {
+                self.error("Can't return a value from an initializer.");
+            }
+
+            self.expression();
+            self.consume(Token::Semicolon, "Expect ';' after return value.");
+            self.emit_byte(OpCode::OpReturn);
+        }
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(Token::Semicolon, "Expect ';' after value.");
+        self.emit_byte(OpCode::OpPrint);
+    }
+
+    /// An expression evaluated for its side effects, so its value is thrown away afterwards. The
+    /// exception is an expression statement right at the end of a script, whose value is what the
+    /// script returns.
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(Token::Semicolon, "Expect ';' after expression.");
+        self.emit_byte(OpCode::OpPop);
+    }
+
+    /// Whether the declaration starting at the current token is an expression statement
+    fn at_expression_statement(&self) -> bool {
+        !matches!(
+            self.current_token(),
+            Token::Class
+                | Token::Fun
+                | Token::Var
+                | Token::Print
+                | Token::For
+                | Token::If
+                | Token::Return
+                | Token::While
+                | Token::LeftBrace
+        )
+    }
+
+    /// Return the value of the expression statement just compiled, instead of popping it
+    fn return_last_value(&mut self) {
+        if let Some(op) = self.chunk().code.last_mut() {
+            if *op == OpCode::OpPop {
+                *op = OpCode::OpReturn;
+            }
+        }
+    }
+
+    /// Consume the name in a variable declaration. For a global, this adds its name to the
+    /// constant table and returns the index. Locals don't need that, so we just return 0.
+    fn parse_variable(&mut self, message: &str) -> usize {
+        let name = match self.current_token() {
+            Token::Identifier(name) => name,
+            _ => {
+                self.error_at_current(message);
+                return 0;
+            }
+        };
+        self.advance();
+
+        if self.compiler.scope_depth > 0 {
+            self.declare_local(name);
+            return 0;
+        }
+
+        self.identifier_constant(&name)
+    }
+
+    fn declare_local(&mut self, name: String) {
+        let scope_depth = self.compiler.scope_depth;
+        let already_declared = self
+            .compiler
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .any(|local| local.name == name);
+
+        if already_declared {
+            self.error("Already a variable with this name in this scope.");
+        }
+
+        if self.compiler.locals.len() >= MAX_LOCALS {
+            self.error("Too many local variables in function.");
+            return;
+        }
+
+        self.compiler.locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    /// Make a declared variable available for use. Locals already have their value on the stack,
+    /// so all they need is to be marked as initialized.
+    fn define_variable(&mut self, global: usize) {
+        if self.compiler.scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_byte(OpCode::OpDefineGlobal(global));
+    }
+
+    /// Mark the most recently declared local as ready for use
+    fn mark_initialized(&mut self) {
+        let scope_depth = self.compiler.scope_depth;
+        if scope_depth == 0 {
+            // globals are defined by `OpDefineGlobal` instead
+            return;
+        }
+
+        if let Some(local) = self.compiler.locals.last_mut() {
+            local.depth = Some(scope_depth);
+        }
+    }
+
+    fn resolve_local(&mut self, name: &str) -> Option<usize> {
+        self.compiler.resolve_local(name).unwrap_or_else(|message| {
+            self.error(message);
+            // carry on as though the variable was found, the code is never going to run
+            Some(0)
+        })
+    }
+
+    fn resolve_upvalue(&mut self, name: &str) -> Option<usize> {
+        self.compiler
+            .resolve_upvalue(name)
+            .unwrap_or_else(|message| {
+                self.error(message);
+                Some(0)
+            })
+    }
+
+    // The Pratt parser proper. Every token has a `ParseRule` (see `rule_for`) which says how to
+    // parse it when it shows up at the start of an expression (prefix) or after a left-hand
+    // operand (infix), and how tightly it binds. `parse_precedence` parses anything at the
+    // given precedence level or higher.
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::PrecAssignment);
+    }
+
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        self.advance();
+
+        let prefix_rule = match rule_for(&self.previous_token()).prefix {
+            Some(rule) => rule,
+            None => {
+                self.error("Expect expression.");
+                return;
+            }
+        };
+
+        // Only a prefix expression parsed at assignment precedence can be the target of an
+        // assignment, otherwise `a * b = c` would parse as `a * (b = c)`.
+        let can_assign = precedence <= Precedence::PrecAssignment;
+        prefix_rule(self, can_assign);
+
+        while precedence <= precedence_for_op(&self.current_token()) {
+            self.advance();
+            if let Some(infix_rule) = rule_for(&self.previous_token()).infix {
+                infix_rule(self, can_assign);
+            }
+        }
+
+        if can_assign && self.matches(Token::Equal) {
+            self.error("Invalid assignment target.");
+        }
     }
 
-    // parser itself, I'm not sure this can be easily ported to Rust
-    // instead, lets read through the whole chapter, understand how the Pratt parser works,
-    // and then implement our own.
-    fn expression(self) {
-        return self;
-        
+    fn variable(&mut self, can_assign: bool) {
+        if let Token::Identifier(name) = self.previous_token() {
+            self.named_variable(&name, can_assign);
+        }
+    }
+
+    fn named_variable(&mut self, name: &str, can_assign: bool) {
+        let (get_op, set_op) = if let Some(slot) = self.resolve_local(name) {
+            (OpCode::OpGetLocal(slot), OpCode::OpSetLocal(slot))
+        } else if let Some(index) = self.resolve_upvalue(name) {
+            (OpCode::OpGetUpvalue(index), OpCode::OpSetUpvalue(index))
+        } else {
+            let global = self.identifier_constant(name);
+            (OpCode::OpGetGlobal(global), OpCode::OpSetGlobal(global))
+        };
+
+        if can_assign && self.matches(Token::Equal) {
+            self.expression();
+            self.emit_byte(set_op);
+        } else {
+            self.emit_byte(get_op);
+        }
     }
 
-    fn number(self, value: Value) {
-        self.emit_constant(value);
+    fn this(&mut self, _can_assign: bool) {
+        if self.classes.is_empty() {
+            self.error("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        // `this` is just a local living in slot 0 of every method, and can't be assigned to
+        self.named_variable("this", false);
     }
 
-    fn grouping(self) {
+    fn super_(&mut self, _can_assign: bool) {
+        match self.classes.last() {
+            None => self.error("Can't use 'super' outside of a class."),
+            Some(class) if !class.has_superclass => {
+                self.error("Can't use 'super' in a class with no superclass.")
+            }
+            _ => (),
+        }
+
+        self.consume(Token::Dot, "Expect '.' after 'super'.");
+        let name = match self.current_token() {
+            Token::Identifier(name) => name,
+            _ => {
+                self.error_at_current("Expect superclass method name.");
+                return;
+            }
+        };
+        self.advance();
+        let name = self.identifier_constant(&name);
+
+        // the method gets bound to `this`, but looked up starting from the superclass
+        self.named_variable("this", false);
+        if self.matches(Token::LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable("super", false);
+            self.emit_byte(OpCode::OpSuperInvoke(name, arg_count));
+        } else {
+            self.named_variable("super", false);
+            self.emit_byte(OpCode::OpGetSuper(name));
+        }
+    }
+
+    fn number(&mut self, _can_assign: bool) {
+        if let Token::Number(value) = self.previous_token() {
+            self.emit_constant(Value::Number(value));
+        }
+    }
+
+    fn string(&mut self, _can_assign: bool) {
+        if let Token::String(chars) = self.previous_token() {
+            self.emit_string(chars);
+        }
+    }
+
+    /// A string with interpolated expressions, which is compiled as a chain of concatenations:
+    /// "a ${b} c" becomes "a " + b + " c", with `b` turned into a string first
+    fn interpolation(&mut self, _can_assign: bool) {
+        let mut first_part = true;
+
+        while let Token::Interpolation(chars) = self.previous_token() {
+            self.emit_string(chars);
+            if !first_part {
+                self.emit_byte(OpCode::OpAdd);
+            }
+            first_part = false;
+
+            self.expression();
+            self.emit_byte(OpCode::OpToString);
+            self.emit_byte(OpCode::OpAdd);
+
+            // the scanner picks the string up again after the expression's closing brace
+            match self.current_token() {
+                Token::Interpolation(_) => self.advance(),
+                Token::InterpolationEnd(chars) => {
+                    self.advance();
+                    self.emit_string(chars);
+                    self.emit_byte(OpCode::OpAdd);
+                }
+                _ => self.error_at_current("Expect '}' after interpolated expression."),
+            }
+        }
+    }
+
+    fn emit_string(&mut self, chars: String) {
+        let string = self.collecting_heap().take_string(chars);
+        self.emit_constant(Value::Obj(string));
+    }
+
+    fn literal(&mut self, _can_assign: bool) {
+        match self.previous_token() {
+            Token::False => self.emit_byte(OpCode::OpFalse),
+            Token::Nil => self.emit_byte(OpCode::OpNil),
+            Token::True => self.emit_byte(OpCode::OpTrue),
+            _ => (),
+        }
+    }
+
+    fn grouping(&mut self, _can_assign: bool) {
         self.expression();
-        self.consume(Token::RightParen, String::from("Expect ')' after expression."));
+        self.consume(Token::RightParen, "Expect ')' after expression.");
     }
 
+    fn unary(&mut self, _can_assign: bool) {
+        let operator = self.previous_token();
 
-    // fn unary(self) {
+        // compile the operand
+        self.parse_precedence(Precedence::PrecUnary);
+
+        match operator {
+            Token::Bang => self.emit_byte(OpCode::OpNot),
+            Token::Minus => self.emit_byte(OpCode::OpNegate),
+            _ => (),
+        }
+    }
+
+    fn call(&mut self, _can_assign: bool) {
+        let arg_count = self.argument_list();
+        self.emit_byte(OpCode::OpCall(arg_count));
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        let name = match self.current_token() {
+            Token::Identifier(name) => name,
+            _ => {
+                self.error_at_current("Expect property name after '.'.");
+                return;
+            }
+        };
+        self.advance();
+        let name = self.identifier_constant(&name);
+
+        if can_assign && self.matches(Token::Equal) {
+            self.expression();
+            self.emit_byte(OpCode::OpSetProperty(name));
+        } else if self.matches(Token::LeftParen) {
+            let arg_count = self.argument_list();
+            self.emit_byte(OpCode::OpInvoke(name, arg_count));
+        } else {
+            self.emit_byte(OpCode::OpGetProperty(name));
+        }
+    }
+
+    /// Compile the arguments to a call, returning how many there were
+    fn argument_list(&mut self) -> usize {
+        let mut arg_count = 0;
+
+        if !self.check(&Token::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == MAX_ARGUMENTS {
+                    self.error(&format!(
+                        "Can't have more than {} arguments.",
+                        MAX_ARGUMENTS
+                    ));
+                }
+                arg_count += 1;
+
+                if !self.matches(Token::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(Token::RightParen, "Expect ')' after arguments.");
+
+        arg_count
+    }
+
+    fn and(&mut self, _can_assign: bool) {
+        // if the left-hand side is falsey, it's the result and we skip the right-hand side
+        let end_jump = self.emit_jump(OpCode::OpJumpIfFalse(0));
+
+        self.emit_byte(OpCode::OpPop);
+        self.parse_precedence(Precedence::PrecAnd);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self, _can_assign: bool) {
+        // if the left-hand side is truthy, it's the result and we skip the right-hand side
+        let else_jump = self.emit_jump(OpCode::OpJumpIfFalse(0));
+        let end_jump = self.emit_jump(OpCode::OpJump(0));
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::OpPop);
+
+        self.parse_precedence(Precedence::PrecOr);
+        self.patch_jump(end_jump);
+    }
+
+    fn binary(&mut self, _can_assign: bool) {
+        let operator = self.previous_token();
+        let span = self
+            .previous
+            .as_ref()
+            .map_or(Span::default(), |prev| prev.span);
+
+        // the right-hand operand binds one level tighter than the operator itself, which is what
+        // makes the binary operators left-associative
+        self.parse_precedence(precedence_for_op(&operator).next());
+
+        match operator {
+            Token::BangEqual => {
+                self.emit_byte_at(OpCode::OpEqual, span);
+                self.emit_byte_at(OpCode::OpNot, span);
+            }
+            Token::EqualEqual => self.emit_byte_at(OpCode::OpEqual, span),
+            Token::Greater => self.emit_byte_at(OpCode::OpGreater, span),
+            Token::GreaterEqual => {
+                self.emit_byte_at(OpCode::OpLess, span);
+                self.emit_byte_at(OpCode::OpNot, span);
+            }
+            Token::Less => self.emit_byte_at(OpCode::OpLess, span),
+            Token::LessEqual => {
+                self.emit_byte_at(OpCode::OpGreater, span);
+                self.emit_byte_at(OpCode::OpNot, span);
+            }
+            Token::Plus => self.emit_byte_at(OpCode::OpAdd, span),
+            Token::Minus => self.emit_byte_at(OpCode::OpSubtract, span),
+            Token::Star => self.emit_byte_at(OpCode::OpMultiply, span),
+            Token::Slash => self.emit_byte_at(OpCode::OpDivide, span),
+            _ => (),
+        }
+    }
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub(crate) enum Precedence {
+    PrecNone,
+    PrecAssignment, // =
+    PrecOr,         // or
+    PrecAnd,        // and
+    PrecEquality,   // == !=
+    PrecComparison, // < > <= >=
+    PrecTerm,       // + -
+    PrecFactor,     // * /
+    PrecUnary,      // ! -
+    PrecCall,       // . ()
+    PrecPrimary,
+}
+
+impl Precedence {
+    /// The next-highest precedence level
+    pub(crate) fn next(&self) -> Precedence {
+        match self {
+            Precedence::PrecNone => Precedence::PrecAssignment,
+            Precedence::PrecAssignment => Precedence::PrecOr,
+            Precedence::PrecOr => Precedence::PrecAnd,
+            Precedence::PrecAnd => Precedence::PrecEquality,
+            Precedence::PrecEquality => Precedence::PrecComparison,
+            Precedence::PrecComparison => Precedence::PrecTerm,
+            Precedence::PrecTerm => Precedence::PrecFactor,
+            Precedence::PrecFactor => Precedence::PrecUnary,
+            Precedence::PrecUnary => Precedence::PrecCall,
+            Precedence::PrecCall => Precedence::PrecPrimary,
+            Precedence::PrecPrimary => Precedence::PrecPrimary,
+        }
+    }
 }
 
-fn error_message_for(token_info: &TokenInfo) -> String {
-    match token_info.token.clone() {
-        Token::EOF => String::from(" at end"),
-        Token::Identifier(identifier) => format!(" at '{}'", identifier),
-        Token::String(str) => format!(" at '{}'", str),
-        Token::Number(num) => format!(" at '{}'", num),
-        _ => String::from(""),
+pub(crate) fn precedence_for_op(op: &Token) -> Precedence {
+    match op {
+        Token::Or => Precedence::PrecOr,
+        Token::And => Precedence::PrecAnd,
+        Token::BangEqual | Token::EqualEqual => Precedence::PrecEquality,
+        Token::Greater | Token::GreaterEqual | Token::Less | Token::LessEqual => {
+            Precedence::PrecComparison
+        }
+        Token::Minus | Token::Plus => Precedence::PrecTerm,
+        Token::Slash | Token::Star => Precedence::PrecFactor,
+        Token::LeftParen | Token::Dot => Precedence::PrecCall,
+        _ => Precedence::PrecNone,
     }
 }
 
-fn error_at(token_info: &TokenInfo, message: String) {
-    error!("[line {}] Error{}", token_info.line, message);
+// the `bool` says whether the expression being parsed may be the target of an assignment
+type ParseFn<'a> = fn(&mut Parser<'a>, bool);
+
+struct ParseRule<'a> {
+    prefix: Option<ParseFn<'a>>,
+    infix: Option<ParseFn<'a>>,
 }
 
-enum Precedence {
-  PrecNone,
-  PrecAssignment,  // =
-  PrecOr,          // or
-  PrecAnd,         // and
-  PrecEquality,    // == !=
-  PrecComparison,  // < > <= >=
-  PrecTerm,        // + -
-  PrecFactor,      // * /
-  PrecUnary,       // ! -
-  PrecCall,        // . ()
-  PrecPrimary
+/// Look up the prefix and infix parse functions for a token. The precedence of a token used as
+/// an infix operator comes from `precedence_for_op`.
+fn rule_for<'a>(token: &Token) -> ParseRule<'a> {
+    let (prefix, infix): (Option<ParseFn<'a>>, Option<ParseFn<'a>>) = match token {
+        Token::LeftParen => (Some(Parser::grouping), Some(Parser::call)),
+        Token::Dot => (None, Some(Parser::dot)),
+        Token::Minus => (Some(Parser::unary), Some(Parser::binary)),
+        Token::Plus | Token::Slash | Token::Star => (None, Some(Parser::binary)),
+        Token::Bang => (Some(Parser::unary), None),
+        Token::And => (None, Some(Parser::and)),
+        Token::Or => (None, Some(Parser::or)),
+        Token::BangEqual
+        | Token::EqualEqual
+        | Token::Greater
+        | Token::GreaterEqual
+        | Token::Less
+        | Token::LessEqual => (None, Some(Parser::binary)),
+        Token::Identifier(_) => (Some(Parser::variable), None),
+        Token::This => (Some(Parser::this), None),
+        Token::Super => (Some(Parser::super_), None),
+        Token::String(_) => (Some(Parser::string), None),
+        Token::Interpolation(_) => (Some(Parser::interpolation), None),
+        Token::Number(_) => (Some(Parser::number), None),
+        Token::False | Token::Nil | Token::True => (Some(Parser::literal), None),
+        _ => (None, None),
+    };
+    ParseRule { prefix, infix }
 }
 
-fn precedence_for_op(op: Token) -> Precedence {
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_compiles_to(source: &str, expected: Vec<OpCode>) {
+        // the heap has to outlive the script, which points into it
+        let mut heap = Heap::init();
+        let script = compile(source, &mut heap).unwrap();
+        let chunk = &script.as_function().unwrap().chunk;
+
+        if chunk.code != expected {
+            println!("didnt match: {:?} and {:?}", chunk.code, expected);
+        }
+        assert!(chunk.code == expected);
+    }
+
+    /// Check the code for `source` compiled as the expression statement ending a script, leaving
+    /// off the return of its value and the script's implicit return after that
+    fn test_expression_compiles_to(source: &str, mut expected: Vec<OpCode>) {
+        expected.push(OpCode::OpReturn);
+        expected.push(OpCode::OpNil);
+        expected.push(OpCode::OpReturn);
+        test_compiles_to(&format!("{};", source), expected);
+    }
+
+    #[test]
+    fn single_number() {
+        test_expression_compiles_to("1", vec![OpCode::OpConstant(0)]);
+    }
+
+    #[test]
+    fn negation() {
+        test_expression_compiles_to("-1", vec![OpCode::OpConstant(0), OpCode::OpNegate]);
+        test_expression_compiles_to(
+            "--1",
+            vec![OpCode::OpConstant(0), OpCode::OpNegate, OpCode::OpNegate],
+        );
+    }
+
+    #[test]
+    fn binary_operators() {
+        for (source, op) in [
+            ("1 + 2", OpCode::OpAdd),
+            ("1 - 2", OpCode::OpSubtract),
+            ("1 * 2", OpCode::OpMultiply),
+            ("1 / 2", OpCode::OpDivide),
+        ] {
+            test_expression_compiles_to(
+                source,
+                vec![OpCode::OpConstant(0), OpCode::OpConstant(1), op],
+            );
+        }
+    }
+
+    #[test]
+    fn factor_binds_tighter_than_term() {
+        test_expression_compiles_to(
+            "1 + 2 * 3",
+            vec![
+                OpCode::OpConstant(0),
+                OpCode::OpConstant(1),
+                OpCode::OpConstant(2),
+                OpCode::OpMultiply,
+                OpCode::OpAdd,
+            ],
+        );
+        test_expression_compiles_to(
+            "1 * 2 + 3",
+            vec![
+                OpCode::OpConstant(0),
+                OpCode::OpConstant(1),
+                OpCode::OpMultiply,
+                OpCode::OpConstant(2),
+                OpCode::OpAdd,
+            ],
+        );
+    }
+
+    #[test]
+    fn binary_operators_are_left_associative() {
+        test_expression_compiles_to(
+            "1 - 2 - 3",
+            vec![
+                OpCode::OpConstant(0),
+                OpCode::OpConstant(1),
+                OpCode::OpSubtract,
+                OpCode::OpConstant(2),
+                OpCode::OpSubtract,
+            ],
+        );
+    }
+
+    #[test]
+    fn unary_binds_tighter_than_binary() {
+        test_expression_compiles_to(
+            "-1 * 2",
+            vec![
+                OpCode::OpConstant(0),
+                OpCode::OpNegate,
+                OpCode::OpConstant(1),
+                OpCode::OpMultiply,
+            ],
+        );
+    }
+
+    #[test]
+    fn grouping_overrides_precedence() {
+        test_expression_compiles_to(
+            "(1 + 2) * 3",
+            vec![
+                OpCode::OpConstant(0),
+                OpCode::OpConstant(1),
+                OpCode::OpAdd,
+                OpCode::OpConstant(2),
+                OpCode::OpMultiply,
+            ],
+        );
+    }
+
+    #[test]
+    fn literals() {
+        test_expression_compiles_to("nil", vec![OpCode::OpNil]);
+        test_expression_compiles_to("true", vec![OpCode::OpTrue]);
+        test_expression_compiles_to("false", vec![OpCode::OpFalse]);
+        test_expression_compiles_to("!true", vec![OpCode::OpTrue, OpCode::OpNot]);
+    }
+
+    #[test]
+    fn comparisons() {
+        for (source, ops) in [
+            ("1 == 2", vec![OpCode::OpEqual]),
+            ("1 != 2", vec![OpCode::OpEqual, OpCode::OpNot]),
+            ("1 > 2", vec![OpCode::OpGreater]),
+            ("1 >= 2", vec![OpCode::OpLess, OpCode::OpNot]),
+            ("1 < 2", vec![OpCode::OpLess]),
+            ("1 <= 2", vec![OpCode::OpGreater, OpCode::OpNot]),
+        ] {
+            let mut expected = vec![OpCode::OpConstant(0), OpCode::OpConstant(1)];
+            expected.extend(ops);
+            test_expression_compiles_to(source, expected);
+        }
+    }
+
+    #[test]
+    fn comparison_binds_tighter_than_equality() {
+        test_expression_compiles_to(
+            "1 < 2 == 3 + 4 > 5",
+            vec![
+                OpCode::OpConstant(0),
+                OpCode::OpConstant(1),
+                OpCode::OpLess,
+                OpCode::OpConstant(2),
+                OpCode::OpConstant(3),
+                OpCode::OpAdd,
+                OpCode::OpConstant(4),
+                OpCode::OpGreater,
+                OpCode::OpEqual,
+            ],
+        );
+    }
+
+    #[test]
+    fn string_constants_are_interned() {
+        let mut heap = Heap::init();
+        let script = compile("\"foo\" + \"foo\" + \"bar\";", &mut heap).unwrap();
+        let chunk = &script.as_function().unwrap().chunk;
+
+        assert!(chunk.constants[0] == chunk.constants[1]);
+        assert!(chunk.constants[0] != chunk.constants[2]);
+        assert!(chunk.constants[2] == Value::Obj(heap.copy_string("bar")));
+    }
+
+    #[test]
+    fn interpolation_is_concatenation() {
+        test_expression_compiles_to(
+            r#""a ${1} b ${2} c""#,
+            vec![
+                OpCode::OpConstant(0),
+                OpCode::OpConstant(1),
+                OpCode::OpToString,
+                OpCode::OpAdd,
+                OpCode::OpConstant(2),
+                OpCode::OpAdd,
+                OpCode::OpConstant(3),
+                OpCode::OpToString,
+                OpCode::OpAdd,
+                OpCode::OpConstant(4),
+                OpCode::OpAdd,
+            ],
+        );
+
+        let messages: Vec<String> = compile_errors(r#"print "${1 2}";"#)
+            .into_iter()
+            .map(|error| error.message)
+            .collect();
+        assert_eq!(messages, vec!["Expect '}' after interpolated expression."]);
+    }
+
+    #[test]
+    fn print_statement() {
+        test_compiles_to(
+            "print 1 + 2;",
+            vec![
+                OpCode::OpConstant(0),
+                OpCode::OpConstant(1),
+                OpCode::OpAdd,
+                OpCode::OpPrint,
+                OpCode::OpNil,
+                OpCode::OpReturn,
+            ],
+        );
+    }
+
+    #[test]
+    fn global_variables() {
+        test_compiles_to(
+            "var x = 1; x = x + 2; print x;",
+            vec![
+                OpCode::OpConstant(1),
+                OpCode::OpDefineGlobal(0),
+                OpCode::OpGetGlobal(3),
+                OpCode::OpConstant(4),
+                OpCode::OpAdd,
+                OpCode::OpSetGlobal(2),
+                OpCode::OpPop,
+                OpCode::OpGetGlobal(5),
+                OpCode::OpPrint,
+                OpCode::OpNil,
+                OpCode::OpReturn,
+            ],
+        );
+        test_compiles_to(
+            "var x;",
+            vec![
+                OpCode::OpNil,
+                OpCode::OpDefineGlobal(0),
+                OpCode::OpNil,
+                OpCode::OpReturn,
+            ],
+        );
+    }
+
+    #[test]
+    fn assignment_is_right_associative() {
+        test_compiles_to(
+            "a = b = 1;",
+            vec![
+                OpCode::OpConstant(2),
+                OpCode::OpSetGlobal(1),
+                OpCode::OpSetGlobal(0),
+                OpCode::OpReturn,
+                OpCode::OpNil,
+                OpCode::OpReturn,
+            ],
+        );
+    }
+
+    #[test]
+    fn local_variables() {
+        test_compiles_to(
+            "{ var a = 1; { var b = a; b = 2; } a; }",
+            vec![
+                OpCode::OpConstant(0),
+                OpCode::OpGetLocal(1),
+                OpCode::OpConstant(1),
+                OpCode::OpSetLocal(2),
+                OpCode::OpPop,
+                OpCode::OpPop,
+                OpCode::OpGetLocal(1),
+                OpCode::OpPop,
+                OpCode::OpPop,
+                OpCode::OpNil,
+                OpCode::OpReturn,
+            ],
+        );
+    }
+
+    #[test]
+    fn locals_shadow_outer_variables() {
+        test_compiles_to(
+            "var a = 1; { var a = 2; a; } a;",
+            vec![
+                OpCode::OpConstant(1),
+                OpCode::OpDefineGlobal(0),
+                OpCode::OpConstant(2),
+                OpCode::OpGetLocal(1),
+                OpCode::OpPop,
+                OpCode::OpPop,
+                OpCode::OpGetGlobal(3),
+                OpCode::OpReturn,
+                OpCode::OpNil,
+                OpCode::OpReturn,
+            ],
+        );
+    }
+
+    #[test]
+    fn local_variable_errors() {
+        let mut heap = Heap::init();
+        assert!(compile("{ var a = 1; var a = 2; }", &mut heap).is_err());
+        assert!(compile("{ var a = a; }", &mut heap).is_err());
+        assert!(compile("{ var a = 1; { var a = 2; } }", &mut heap).is_ok());
+        assert!(compile("{ var a = 1;", &mut heap).is_err());
+
+        // the first slot is always taken by the function being called
+        let too_many: String = (0..MAX_LOCALS).map(|i| format!("var v{};", i)).collect();
+        assert!(compile(&format!("{{ {} }}", too_many), &mut heap).is_err());
+        let just_enough: String = (1..MAX_LOCALS).map(|i| format!("var v{};", i)).collect();
+        assert!(compile(&format!("{{ {} }}", just_enough), &mut heap).is_ok());
+    }
+
+    #[test]
+    fn if_else() {
+        test_compiles_to(
+            "if (true) print 1; else print 2;",
+            vec![
+                OpCode::OpTrue,
+                OpCode::OpJumpIfFalse(4),
+                OpCode::OpPop,
+                OpCode::OpConstant(0),
+                OpCode::OpPrint,
+                OpCode::OpJump(3),
+                OpCode::OpPop,
+                OpCode::OpConstant(1),
+                OpCode::OpPrint,
+                OpCode::OpNil,
+                OpCode::OpReturn,
+            ],
+        );
+    }
+
+    #[test]
+    fn while_loop() {
+        test_compiles_to(
+            "while (false) print 1;",
+            vec![
+                OpCode::OpFalse,
+                OpCode::OpJumpIfFalse(4),
+                OpCode::OpPop,
+                OpCode::OpConstant(0),
+                OpCode::OpPrint,
+                OpCode::OpLoop(6),
+                OpCode::OpPop,
+                OpCode::OpNil,
+                OpCode::OpReturn,
+            ],
+        );
+    }
+
+    #[test]
+    fn logical_operators() {
+        test_expression_compiles_to(
+            "true and false",
+            vec![
+                OpCode::OpTrue,
+                OpCode::OpJumpIfFalse(2),
+                OpCode::OpPop,
+                OpCode::OpFalse,
+            ],
+        );
+        test_expression_compiles_to(
+            "true or false",
+            vec![
+                OpCode::OpTrue,
+                OpCode::OpJumpIfFalse(1),
+                OpCode::OpJump(2),
+                OpCode::OpPop,
+                OpCode::OpFalse,
+            ],
+        );
+    }
+
+    #[test]
+    fn jumps_must_fit_in_a_u16() {
+        let mut heap = Heap::init();
+        // every `nil;` statement compiles to two instructions
+        let body = "nil;".repeat(u16::MAX as usize / 2 + 1);
+
+        assert!(compile(&format!("if (true) {{ {} }}", body), &mut heap).is_err());
+        assert!(compile(&format!("while (true) {{ {} }}", body), &mut heap).is_err());
+
+        let short_body = "nil;".repeat(u16::MAX as usize / 2 - 4);
+        assert!(compile(&format!("if (true) {{ {} }}", short_body), &mut heap).is_ok());
+    }
+
+    #[test]
+    fn function_declaration() {
+        let mut heap = Heap::init();
+        let script = compile("fun add(a, b) { return a + b; } add(1, 2);", &mut heap).unwrap();
+        let chunk = &script.as_function().unwrap().chunk;
+
+        assert!(
+            chunk.code
+                == vec![
+                    OpCode::OpClosure(1),
+                    OpCode::OpDefineGlobal(0),
+                    OpCode::OpGetGlobal(2),
+                    OpCode::OpConstant(3),
+                    OpCode::OpConstant(4),
+                    OpCode::OpCall(2),
+                    OpCode::OpReturn,
+                    OpCode::OpNil,
+                    OpCode::OpReturn,
+                ]
+        );
+
+        let add = match chunk.constants[1] {
+            Value::Obj(obj) => obj,
+            _ => panic!("expected the function to be a constant"),
+        };
+        let add = add.as_function().unwrap();
+        assert_eq!(add.arity, 2);
+        assert_eq!(add.to_string(), "<fn add>");
+        assert!(
+            add.chunk.code
+                == vec![
+                    OpCode::OpGetLocal(1),
+                    OpCode::OpGetLocal(2),
+                    OpCode::OpAdd,
+                    OpCode::OpReturn,
+                    OpCode::OpNil,
+                    OpCode::OpReturn,
+                ]
+        );
+    }
+
+    #[test]
+    fn closures_capture_upvalues() {
+        let mut heap = Heap::init();
+        let source = "fun outer() { var a = 1; var b = 2; fun inner() { return b + a; } }";
+        let script = compile(source, &mut heap).unwrap();
+
+        let constant = |chunk: &Chunk, index: usize| match chunk.constants[index] {
+            Value::Obj(obj) => obj,
+            _ => panic!("expected a function constant"),
+        };
+        let outer = constant(&script.as_function().unwrap().chunk, 1);
+        let outer = outer.as_function().unwrap();
+        let inner = constant(&outer.chunk, 2);
+        let inner = inner.as_function().unwrap();
+
+        assert!(
+            inner.upvalues
+                == vec![
+                    UpvalueDescriptor {
+                        index: 2,
+                        is_local: true
+                    },
+                    UpvalueDescriptor {
+                        index: 1,
+                        is_local: true
+                    },
+                ]
+        );
+        assert!(
+            inner.chunk.code
+                == vec![
+                    OpCode::OpGetUpvalue(0),
+                    OpCode::OpGetUpvalue(1),
+                    OpCode::OpAdd,
+                    OpCode::OpReturn,
+                    OpCode::OpNil,
+                    OpCode::OpReturn,
+                ]
+        );
+        // captured locals are closed rather than popped when they go out of scope
+        assert!(
+            outer.chunk.code
+                == vec![
+                    OpCode::OpConstant(0),
+                    OpCode::OpConstant(1),
+                    OpCode::OpClosure(2),
+                    OpCode::OpNil,
+                    OpCode::OpReturn,
+                ]
+        );
+        let source = "{ var a = 1; fun f() { return a; } }";
+        test_compiles_to(
+            source,
+            vec![
+                OpCode::OpConstant(0),
+                OpCode::OpClosure(1),
+                OpCode::OpPop,
+                OpCode::OpCloseUpvalue,
+                OpCode::OpNil,
+                OpCode::OpReturn,
+            ],
+        );
+    }
+
+    #[test]
+    fn class_declaration() {
+        let mut heap = Heap::init();
+        let source = "class Point { init(x) { this.x = x; } getX() { return this.x; } }";
+        let script = compile(source, &mut heap).unwrap();
+        let chunk = &script.as_function().unwrap().chunk;
+
+        assert!(
+            chunk.code
+                == vec![
+                    OpCode::OpClass(0),
+                    OpCode::OpDefineGlobal(0),
+                    OpCode::OpGetGlobal(1),
+                    OpCode::OpClosure(3),
+                    OpCode::OpMethod(2),
+                    OpCode::OpClosure(5),
+                    OpCode::OpMethod(4),
+                    OpCode::OpPop,
+                    OpCode::OpNil,
+                    OpCode::OpReturn,
+                ]
+        );
+
+        let method = |index: usize| match chunk.constants[index] {
+            Value::Obj(obj) => obj,
+            _ => panic!("expected a method constant"),
+        };
+        // an initializer implicitly returns `this`, which lives in slot 0
+        assert!(
+            method(3).as_function().unwrap().chunk.code
+                == vec![
+                    OpCode::OpGetLocal(0),
+                    OpCode::OpGetLocal(1),
+                    OpCode::OpSetProperty(0),
+                    OpCode::OpPop,
+                    OpCode::OpGetLocal(0),
+                    OpCode::OpReturn,
+                ]
+        );
+        assert!(
+            method(5).as_function().unwrap().chunk.code
+                == vec![
+                    OpCode::OpGetLocal(0),
+                    OpCode::OpGetProperty(0),
+                    OpCode::OpReturn,
+                    OpCode::OpNil,
+                    OpCode::OpReturn,
+                ]
+        );
+    }
+
+    #[test]
+    fn property_access() {
+        test_compiles_to(
+            "a.b.c = d.e(1);",
+            vec![
+                OpCode::OpGetGlobal(0),
+                OpCode::OpGetProperty(1),
+                OpCode::OpGetGlobal(3),
+                OpCode::OpConstant(5),
+                OpCode::OpInvoke(4, 1),
+                OpCode::OpSetProperty(2),
+                OpCode::OpReturn,
+                OpCode::OpNil,
+                OpCode::OpReturn,
+            ],
+        );
+    }
+
+    #[test]
+    fn class_errors() {
+        let mut heap = Heap::init();
+        assert!(compile("this;", &mut heap).is_err());
+        assert!(compile("fun f() { return this; }", &mut heap).is_err());
+        assert!(compile("class A { init() { return 1; } }", &mut heap).is_err());
+        assert!(compile("class A { init() { return; } }", &mut heap).is_ok());
+        assert!(compile("class A { f() { this = 1; } }", &mut heap).is_err());
+        assert!(compile("a.1;", &mut heap).is_err());
+        assert!(compile("class { }", &mut heap).is_err());
+        assert!(compile("class A { var x; }", &mut heap).is_err());
+    }
+
+    #[test]
+    fn inheritance() {
+        let mut heap = Heap::init();
+        let source = "class A {} class B < A { f() { return super.f; } g() { super.g(1); } }";
+        let script = compile(source, &mut heap).unwrap();
+        let chunk = &script.as_function().unwrap().chunk;
+
+        // `super` is a local in a scope wrapped around the class body
+        assert!(
+            chunk.code
+                == vec![
+                    OpCode::OpClass(0),
+                    OpCode::OpDefineGlobal(0),
+                    OpCode::OpGetGlobal(1),
+                    OpCode::OpPop,
+                    OpCode::OpClass(2),
+                    OpCode::OpDefineGlobal(2),
+                    OpCode::OpGetGlobal(3),
+                    OpCode::OpGetGlobal(4),
+                    OpCode::OpInherit,
+                    OpCode::OpGetGlobal(5),
+                    OpCode::OpClosure(7),
+                    OpCode::OpMethod(6),
+                    OpCode::OpClosure(9),
+                    OpCode::OpMethod(8),
+                    OpCode::OpPop,
+                    OpCode::OpCloseUpvalue,
+                    OpCode::OpNil,
+                    OpCode::OpReturn,
+                ]
+        );
+
+        let method = |index: usize| match chunk.constants[index] {
+            Value::Obj(obj) => obj,
+            _ => panic!("expected a method constant"),
+        };
+        assert!(
+            method(7).as_function().unwrap().chunk.code
+                == vec![
+                    OpCode::OpGetLocal(0),
+                    OpCode::OpGetUpvalue(0),
+                    OpCode::OpGetSuper(0),
+                    OpCode::OpReturn,
+                    OpCode::OpNil,
+                    OpCode::OpReturn,
+                ]
+        );
+        assert!(
+            method(9).as_function().unwrap().chunk.code
+                == vec![
+                    OpCode::OpGetLocal(0),
+                    OpCode::OpConstant(1),
+                    OpCode::OpGetUpvalue(0),
+                    OpCode::OpSuperInvoke(0, 1),
+                    OpCode::OpPop,
+                    OpCode::OpNil,
+                    OpCode::OpReturn,
+                ]
+        );
+    }
+
+    #[test]
+    fn inheritance_errors() {
+        let mut heap = Heap::init();
+        assert!(compile("class A < A {}", &mut heap).is_err());
+        assert!(compile("class A < {}", &mut heap).is_err());
+        assert!(compile("super.f();", &mut heap).is_err());
+        assert!(compile("fun f() { super.f(); }", &mut heap).is_err());
+        assert!(compile("class A { f() { super.f(); } }", &mut heap).is_err());
+        assert!(compile("class A {} class B < A { f() { super; } }", &mut heap).is_err());
+        assert!(compile("class A {} class B < A { f() { super.1; } }", &mut heap).is_err());
+    }
+
+    #[test]
+    fn function_errors() {
+        let mut heap = Heap::init();
+        assert!(compile("return 1;", &mut heap).is_err());
+        assert!(compile("fun f() { var a = 1; fun g() { var a = a; } }", &mut heap).is_err());
+        assert!(compile("fun f(a, a) {}", &mut heap).is_err());
+        assert!(compile("fun f(a b) {}", &mut heap).is_err());
+        assert!(compile("fun () {}", &mut heap).is_err());
+        assert!(compile("f(1, 2;", &mut heap).is_err());
+
+        let params: Vec<String> = (0..=MAX_ARGUMENTS).map(|i| format!("p{}", i)).collect();
+        let too_many_params = format!("fun f({}) {{}}", params.join(", "));
+        assert!(compile(&too_many_params, &mut heap).is_err());
+
+        let args = vec!["nil"; MAX_ARGUMENTS + 1].join(", ");
+        assert!(compile(&format!("f({});", args), &mut heap).is_err());
+        let args = vec!["nil"; MAX_ARGUMENTS].join(", ");
+        assert!(compile(&format!("f({});", args), &mut heap).is_ok());
+    }
+
+    #[test]
+    fn errors_fail_compilation() {
+        let mut heap = Heap::init();
+        assert!(compile("1 +", &mut heap).is_err());
+        assert!(compile("(1 + 2", &mut heap).is_err());
+        assert!(compile("1 2", &mut heap).is_err());
+        assert!(compile("var;", &mut heap).is_err());
+        assert!(compile("var x = 1", &mut heap).is_err());
+        assert!(compile("1 + 2", &mut heap).is_err());
+        assert!(compile("print 1", &mut heap).is_err());
+        assert!(compile("print;", &mut heap).is_err());
+        assert!(compile("a * b = c;", &mut heap).is_err());
+    }
+
+    fn compile_errors(source: &str) -> Vec<CompileError> {
+        let mut heap = Heap::init();
+        match compile(source, &mut heap) {
+            Ok(_) => panic!("expected {:?} to fail to compile", source),
+            Err(errors) => errors,
+        }
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_token() {
+        let error = |(start, end): (usize, usize), (line, column), lexeme: &str, message: &str| {
+            CompileError {
+                span: Span {
+                    start,
+                    end,
+                    line,
+                    column,
+                },
+                lexeme: Some(String::from(lexeme)),
+                message: String::from(message),
+            }
+        };
+
+        assert_eq!(
+            compile_errors("var 1 = 2;"),
+            vec![error((4, 5), (1, 5), "1", "Expect variable name.")]
+        );
+        assert_eq!(
+            compile_errors("\nprint 1"),
+            vec![error((8, 8), (2, 8), "", "Expect ';' after value.")]
+        );
+        assert_eq!(
+            compile_errors("a * b = \"c\";"),
+            vec![error((6, 7), (1, 7), "=", "Invalid assignment target.")]
+        );
+        assert_eq!(
+            compile_errors("print \"ünïcode\" +;"),
+            vec![error((19, 20), (1, 18), ";", "Expect expression.")]
+        );
+    }
+
+    #[test]
+    fn every_independent_error_is_reported() {
+        let source = "
+            var = 1;
+            print (1 + ;
+            fun f( { }
+            class A { 1 }
+            while (true) print x
+            var ok = 1;
+            return ok;
+            print 1 2 @ ;
+            print ;
+            print ;
+        ";
+        let messages: Vec<(i32, String)> = compile_errors(source)
+            .into_iter()
+            .map(|error| (error.span.line, error.message))
+            .collect();
+
+        assert_eq!(
+            messages,
+            vec![
+                (2, String::from("Expect variable name.")),
+                (3, String::from("Expect expression.")),
+                (4, String::from("Expect parameter name.")),
+                (5, String::from("Expect method name.")),
+                (7, String::from("Expect ';' after value.")),
+                (8, String::from("Can't return from top-level code.")),
+                (9, String::from("Expect ';' after value.")),
+                (10, String::from("Expect expression.")),
+                (11, String::from("Expect expression.")),
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_display_like_clox() {
+        let errors = compile_errors("var x = ;\nprint 1;\n@ print 2;\nprint 3;\n\"oops");
+        let messages: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "[line 1] Error at ';': Expect expression.",
+                // errors from the scanner don't point at a token
+                "[line 3] Error: Unexpected character '@'.",
+                "[line 5] Error: Unterminated string.",
+            ]
+        );
+
+        let errors = compile_errors("\nprint");
+        assert_eq!(
+            errors[0].to_string(),
+            "[line 2] Error at end: Expect expression."
+        );
+    }
+
+    #[test]
+    fn constants_are_stored_in_order() {
+        let mut heap = Heap::init();
+        let script = compile("(-1 + 2) * 3 - -4;", &mut heap).unwrap();
+        let chunk = &script.as_function().unwrap().chunk;
+        assert!(
+            chunk.constants
+                == vec![
+                    Value::Number(1.0),
+                    Value::Number(2.0),
+                    Value::Number(3.0),
+                    Value::Number(4.0)
+                ]
+        );
+    }
 }
@@ -0,0 +1,343 @@
+//! Turning errors into messages for people and for tools.
+//!
+//! Every `LoxError` breaks down into `Diagnostic`s, one per problem found. A diagnostic can be
+//! rendered like rustc does, with the offending line of source and carets under the code at fault,
+//! or as a line of JSON for editors.
+
+use crate::compiler::CompileError;
+use crate::token::Span;
+use crate::vm::{LoxError, RuntimeError, TraceFrame};
+use std::fmt::Write;
+use std::rc::Rc;
+
+/// Which stage of running a program a diagnostic came from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    Compile,
+    Runtime,
+}
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Compile => "compile",
+            Phase::Runtime => "runtime",
+        }
+    }
+}
+
+/// A single problem with a program, and where in the source it is
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub phase: Phase,
+    pub message: String,
+    // a span on line 0 means the problem has no place in the source
+    pub span: Span,
+    // the source code `span` points into, if that might not be the source being reported on.
+    // Runtime errors have this, since the failing code could be from an earlier REPL line.
+    pub source: Option<Rc<str>>,
+    // the calls in progress, innermost first. Only runtime errors have these.
+    pub trace: Vec<TraceFrame>,
+}
+
+// ANSI escape codes, in the colours rustc uses
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+impl Diagnostic {
+    /// Lay the diagnostic out like rustc, with `file` as the name of the source file. With
+    /// `color`, parts of it are highlighted using ANSI escape codes. The line of source is left
+    /// out if the span doesn't point into it.
+    ///
+    /// ```text
+    /// error: Expect ';' after value.
+    ///  --> script.lox:1:9
+    ///   |
+    /// 1 | print 1 2
+    ///   |         ^
+    /// ```
+    pub fn render(&self, file: &str, source: &str, color: bool) -> String {
+        let source = self.source.as_deref().unwrap_or(source);
+        let paint = |code: &'static str| if color { code } else { "" };
+        let (red, blue, bold, reset) = (paint(RED), paint(BLUE), paint(BOLD), paint(RESET));
+
+        let mut out = format!("{red}error{reset}{bold}: {}{reset}\n", self.message);
+
+        // leave enough room in the gutter for the line number
+        let line_number = self.span.line.to_string();
+        let gutter = " ".repeat(line_number.len());
+
+        if self.has_location() {
+            let _ = writeln!(
+                out,
+                "{gutter}{blue}-->{reset} {}:{}:{}",
+                file, self.span.line, self.span.column
+            );
+            if let (Some(line), Some(carets)) =
+                (self.span.source_line(source), self.span.carets(source))
+            {
+                let _ = writeln!(out, "{gutter} {blue}|{reset}");
+                let _ = writeln!(out, "{blue}{line_number} |{reset} {}", line);
+                let _ = writeln!(out, "{gutter} {blue}|{reset} {red}{}{reset}", carets);
+            }
+        }
+
+        for frame in &self.trace {
+            let _ = writeln!(out, "{gutter} {blue}={reset} {bold}note{reset}: {}", frame);
+        }
+
+        out
+    }
+
+    /// The diagnostic as a single line JSON object, with `file` as the name of the source file.
+    /// `line`, `column`, `start` and `end` are `null` when the diagnostic has no location.
+    pub fn to_json(&self, file: &str, source: &str) -> String {
+        let location = |value: usize| {
+            if self.has_location() {
+                value.to_string()
+            } else {
+                String::from("null")
+            }
+        };
+        let trace: Vec<String> = self
+            .trace
+            .iter()
+            .map(|frame| {
+                format!(
+                    "{{\"function\":{},\"line\":{},\"column\":{}}}",
+                    frame
+                        .function
+                        .as_deref()
+                        .map_or(String::from("null"), json_string),
+                    frame.span.line,
+                    frame.span.column
+                )
+            })
+            .collect();
+
+        format!(
+            "{{\"phase\":{},\"message\":{},\"file\":{},\"line\":{},\"column\":{},\"start\":{},\"end\":{},\"trace\":[{}],\"rendered\":{}}}",
+            json_string(self.phase.name()),
+            json_string(&self.message),
+            json_string(file),
+            location(self.span.line as usize),
+            location(self.span.column),
+            location(self.span.start),
+            location(self.span.end),
+            trace.join(","),
+            json_string(&self.render(file, source, false))
+        )
+    }
+
+    fn has_location(&self) -> bool {
+        self.span.line > 0
+    }
+}
+
+impl From<&CompileError> for Diagnostic {
+    fn from(error: &CompileError) -> Diagnostic {
+        Diagnostic {
+            phase: Phase::Compile,
+            message: error.message.clone(),
+            span: error.span,
+            source: None,
+            trace: vec![],
+        }
+    }
+}
+
+impl From<&RuntimeError> for Diagnostic {
+    fn from(error: &RuntimeError) -> Diagnostic {
+        Diagnostic {
+            phase: Phase::Runtime,
+            message: error.message.clone(),
+            span: error.span,
+            source: Some(Rc::clone(&error.source)),
+            trace: error.trace.clone(),
+        }
+    }
+}
+
+impl LoxError {
+    /// One diagnostic for every problem behind this error, in source order for compile errors
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            LoxError::Compile(errors) => errors.iter().map(Diagnostic::from).collect(),
+            LoxError::Runtime(error) => vec![Diagnostic::from(error)],
+        }
+    }
+}
+
+// quote and escape `text` as a JSON string
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+
+    fn diagnostics_for(source: &str) -> Vec<Diagnostic> {
+        VM::init().interpret(source).unwrap_err().diagnostics()
+    }
+
+    #[test]
+    fn render_compile_errors() {
+        let source = "var a = 1;\nprint a 2;\nvar = 3;";
+        let rendered: Vec<String> = diagnostics_for(source)
+            .iter()
+            .map(|diagnostic| diagnostic.render("test.lox", source, false))
+            .collect();
+
+        assert_eq!(
+            rendered,
+            vec![
+                "error: Expect ';' after value.\n \
+                 --> test.lox:2:9\n  \
+                 |\n\
+                 2 | print a 2;\n  \
+                 |         ^\n",
+                "error: Expect variable name.\n \
+                 --> test.lox:3:5\n  \
+                 |\n\
+                 3 | var = 3;\n  \
+                 |     ^\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn render_runtime_errors_with_their_trace() {
+        let source = "fun f() {\n\n\n\n\n\n\n\n\n  return -nil;\n}\nf();";
+        let diagnostics = diagnostics_for(source);
+
+        // the gutter is as wide as the line number
+        assert_eq!(
+            diagnostics[0].render("test.lox", source, false),
+            "error: Operand must be a number.\n  \
+             --> test.lox:10:11\n   \
+             |\n\
+             10 |   return -nil;\n   \
+             |           ^^^\n   \
+             = note: [line 10] in f()\n   \
+             = note: [line 12] in script\n"
+        );
+    }
+
+    #[test]
+    fn render_in_color() {
+        let source = "print -nil;";
+        let rendered = diagnostics_for(source)[0].render("test.lox", source, true);
+
+        assert!(rendered.starts_with("\x1b[1;31merror\x1b[0m\x1b[1m: Operand must be a number."));
+        assert!(rendered.contains("\x1b[1;34m1 |\x1b[0m print -nil;\n"));
+        assert!(rendered.contains("\x1b[1;31m       ^^^\x1b[0m\n"));
+    }
+
+    #[test]
+    fn render_without_a_location() {
+        let diagnostic = Diagnostic {
+            phase: Phase::Runtime,
+            message: String::from("Out of luck."),
+            span: Span::default(),
+            source: None,
+            trace: vec![],
+        };
+
+        assert_eq!(
+            diagnostic.render("test.lox", "", false),
+            "error: Out of luck.\n"
+        );
+        assert!(diagnostic
+            .to_json("test.lox", "")
+            .contains("\"line\":null,\"column\":null,\"start\":null,\"end\":null"));
+    }
+
+    #[test]
+    fn render_a_span_outside_the_source() {
+        let diagnostic = Diagnostic {
+            phase: Phase::Compile,
+            message: String::from("Out of place."),
+            span: Span {
+                start: 40,
+                end: 43,
+                line: 1,
+                column: 41,
+            },
+            source: None,
+            trace: vec![],
+        };
+
+        // the location is still given, but there's no line of source to show
+        assert_eq!(
+            diagnostic.render("test.lox", "f();\n", false),
+            "error: Out of place.\n --> test.lox:1:41\n"
+        );
+    }
+
+    #[test]
+    fn render_runtime_errors_from_earlier_code() {
+        let mut vm = VM::init();
+        let definition = "fun f() {                                        return -nil; }\n";
+        vm.interpret(definition).unwrap();
+
+        // the error is in the code from the first call, not the one being reported on
+        let source = "f();\n";
+        let diagnostics = vm.interpret(source).unwrap_err().diagnostics();
+        assert_eq!(
+            diagnostics[0].render("<stdin>", source, false),
+            "error: Operand must be a number.\n \
+             --> <stdin>:1:58\n  \
+             |\n\
+             1 | fun f() {                                        return -nil; }\n  \
+             |                                                          ^^^\n  \
+             = note: [line 1] in f()\n  \
+             = note: [line 1] in script\n"
+        );
+    }
+
+    #[test]
+    fn diagnostics_as_json() {
+        let source = "fun f() {\n  return -\"\\t\";\n}\nf();";
+        let diagnostics = diagnostics_for(source);
+
+        assert_eq!(
+            diagnostics[0].to_json("dir\\test.lox", source),
+            concat!(
+                r#"{"phase":"runtime","message":"Operand must be a number.","#,
+                r#""file":"dir\\test.lox","line":2,"column":11,"start":20,"end":24,"#,
+                r#""trace":[{"function":"f","line":2,"column":11},"#,
+                r#"{"function":null,"line":4,"column":3}],"#,
+                r#""rendered":"error: Operand must be a number.\n --> dir\\test.lox:2:11\n  |\n"#,
+                r#"2 |   return -\"\\t\";\n  |           ^^^^\n  = note: [line 2] in f()\n"#,
+                r#"  = note: [line 4] in script\n"}"#
+            )
+        );
+    }
+
+    #[test]
+    fn escape_json_strings() {
+        assert_eq!(json_string("plain"), r#""plain""#);
+        assert_eq!(json_string("a \"b\"\n\\"), r#""a \"b\"\n\\""#);
+        assert_eq!(json_string("\u{1}é"), "\"\\u0001é\"");
+    }
+}
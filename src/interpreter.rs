@@ -0,0 +1,242 @@
+use crate::value::LoxValue;
+use crate::vm::{LoxError, NativeContext, VM};
+use std::io::Write;
+
+/// Runs Lox code on behalf of a host program.
+///
+/// Globals defined by one call to `eval` are still there for the next, so a single interpreter
+/// can run a whole session of code bit by bit.
+pub struct Interpreter {
+    vm: VM,
+}
+
+impl Interpreter {
+    pub fn init() -> Interpreter {
+        Interpreter { vm: VM::init() }
+    }
+
+    /// Compile and run `source`. If it ends with an expression statement, like `1 + 2;`, the
+    /// value of that expression is returned, otherwise the result is `nil`.
+    pub fn eval(&mut self, source: &str) -> Result<LoxValue, LoxError> {
+        let value = self.vm.interpret(source)?;
+        Ok(self.vm.export_value(value))
+    }
+
+    /// Send the output of `print` statements to `output` instead of stdout
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.vm.set_output(Box::new(output));
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<LoxValue> {
+        self.vm
+            .get_global(name)
+            .map(|value| self.vm.export_value(value))
+    }
+
+    /// Define a global variable, or overwrite it if it already exists. This fails if `value` holds
+    /// an `ObjectHandle` from a different interpreter, leaving the globals as they were.
+    pub fn set_global(&mut self, name: &str, value: &LoxValue) -> Result<(), LoxError> {
+        Ok(self.vm.set_host_global(name, value)?)
+    }
+
+    /// Make a Rust function callable from Lox as a global called `name`. Calls with anything other
+    /// than `arity` arguments are rejected before `function` is ever run.
+    pub fn define_native(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl Fn(&mut NativeContext<'_>, &[LoxValue]) -> Result<LoxValue, String> + 'static,
+    ) {
+        self.vm.define_native(name, arity, Box::new(function));
+    }
+
+    /// Limit how deeply calls can be nested before a stack overflow is reported
+    pub fn set_max_frames(&mut self, max_frames: usize) {
+        self.vm.set_max_frames(max_frames);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    /// An output sink the test can still read from after handing it to the interpreter
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn eval_returns_the_final_expression() {
+        let mut interpreter = Interpreter::init();
+        assert_eq!(interpreter.eval("1 + 2;").ok(), Some(LoxValue::Number(3.0)));
+        assert_eq!(interpreter.eval("var a = 1;").ok(), Some(LoxValue::Nil));
+        assert_eq!(interpreter.eval("a; var b = 2;").ok(), Some(LoxValue::Nil));
+        assert_eq!(interpreter.eval("{ a; }").ok(), Some(LoxValue::Nil));
+
+        let result = interpreter.eval("fun f(x) { return x + \"!\"; } f(\"hi\");");
+        assert_eq!(result.ok(), Some(LoxValue::String(String::from("hi!"))));
+    }
+
+    #[test]
+    fn object_handles_keep_their_object_alive() {
+        let mut interpreter = Interpreter::init();
+        let Ok(LoxValue::Object(point)) =
+            interpreter.eval("class Point {} var p = Point(); p.x = 1; p;")
+        else {
+            panic!("expected an object");
+        };
+        assert_eq!(point.to_string(), "Point instance");
+
+        // the handle is the only thing left holding onto the instance through all this garbage
+        interpreter.eval("p = nil;").unwrap();
+        interpreter
+            .eval("for (var i = 0; i < 100000; i = i + 1) { var s = \"x\" + \"y\"; }")
+            .unwrap();
+
+        interpreter
+            .set_global("q", &LoxValue::Object(point.clone()))
+            .unwrap();
+        assert_eq!(interpreter.eval("q.x;").ok(), Some(LoxValue::Number(1.0)));
+        assert_eq!(interpreter.get_global("q"), Some(LoxValue::Object(point)));
+    }
+
+    #[test]
+    fn object_handles_stay_with_their_interpreter() {
+        let mut first = Interpreter::init();
+        let function = first.eval("fun f() {} f;").unwrap();
+
+        let mut second = Interpreter::init();
+        match second.set_global("f", &function) {
+            Err(LoxError::Runtime(error)) => assert_eq!(
+                error.message,
+                "Object handles can only be used with the interpreter they came from."
+            ),
+            _ => panic!("expected a runtime error"),
+        }
+        assert_eq!(second.get_global("f"), None);
+    }
+
+    #[test]
+    fn eval_reports_errors() {
+        let mut interpreter = Interpreter::init();
+        assert!(matches!(
+            interpreter.eval("var;"),
+            Err(LoxError::Compile(_))
+        ));
+        match interpreter.eval("\n-nil;") {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "Operand must be a number.");
+                assert_eq!(error.span.line, 2);
+            }
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn print_goes_to_the_output_sink() {
+        let mut interpreter = Interpreter::init();
+        let output = SharedBuffer::default();
+        interpreter.set_output(output.clone());
+
+        interpreter
+            .eval("print 1; print \"two\"; print nil;")
+            .unwrap();
+        assert_eq!(output.contents(), "1\ntwo\nnil\n");
+    }
+
+    #[test]
+    fn globals_are_shared_with_the_host() {
+        let mut interpreter = Interpreter::init();
+        assert_eq!(interpreter.get_global("missing"), None);
+
+        interpreter
+            .set_global("answer", &LoxValue::Number(42.0))
+            .unwrap();
+        interpreter
+            .set_global("name", &LoxValue::String(String::from("Lox")))
+            .unwrap();
+        interpreter
+            .eval("var doubled = answer * 2; var greeting = \"hi \" + name;")
+            .unwrap();
+        assert_eq!(
+            interpreter.get_global("doubled"),
+            Some(LoxValue::Number(84.0))
+        );
+        assert_eq!(
+            interpreter.get_global("greeting"),
+            Some(LoxValue::String(String::from("hi Lox")))
+        );
+    }
+
+    fn negate(_context: &mut NativeContext, args: &[LoxValue]) -> Result<LoxValue, String> {
+        match args[0] {
+            LoxValue::Number(n) => Ok(LoxValue::Number(-n)),
+            _ => Err(String::from("Expected a number.")),
+        }
+    }
+
+    #[test]
+    fn natives_can_be_registered() {
+        let mut interpreter = Interpreter::init();
+        interpreter.define_native("negate", 1, negate);
+        assert_eq!(
+            interpreter.eval("negate(2);").ok(),
+            Some(LoxValue::Number(-2.0))
+        );
+        match interpreter.eval("\nnegate(nil);") {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, "Expected a number.");
+                assert_eq!(error.span.line, 2);
+            }
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn natives_can_share_state_with_the_host() {
+        let mut interpreter = Interpreter::init();
+        let calls = Rc::new(RefCell::new(vec![]));
+
+        // the closure keeps a log of its calls where the host can see it, and keeps a running
+        // total in a Lox global
+        let log = Rc::clone(&calls);
+        interpreter.define_native("record", 1, move |context, args| {
+            log.borrow_mut().push(args[0].clone());
+            let total = match (context.get_global("total"), &args[0]) {
+                (Some(LoxValue::Number(total)), LoxValue::Number(n)) => total + n,
+                _ => return Err(String::from("Can only record numbers.")),
+            };
+            context
+                .set_global("total", &LoxValue::Number(total))
+                .map_err(|error| error.message)?;
+            Ok(LoxValue::Nil)
+        });
+
+        interpreter
+            .eval("var total = 0; record(1); record(2);")
+            .unwrap();
+        assert_eq!(
+            *calls.borrow(),
+            vec![LoxValue::Number(1.0), LoxValue::Number(2.0)]
+        );
+        assert_eq!(interpreter.get_global("total"), Some(LoxValue::Number(3.0)));
+    }
+}
@@ -0,0 +1,26 @@
+//! A bytecode virtual machine for the Lox language.
+//!
+//! Most embedders only need `Interpreter`, which compiles and runs Lox source and gives access to
+//! its globals. Values cross over to the host as `LoxValue`s, which never point into the
+//! interpreter's heap directly. The scanner is public for tools that want to work with tokens, and
+//! `syntax` parses source into a lossless tree for tools like formatters.
+
+pub(crate) mod chunk;
+pub(crate) mod compiler;
+pub mod diagnostic;
+mod interpreter;
+pub(crate) mod memory;
+pub(crate) mod natives;
+pub(crate) mod object;
+pub mod scanner;
+pub mod syntax;
+pub mod token;
+pub(crate) mod value;
+pub(crate) mod vm;
+
+pub use compiler::CompileError;
+pub use diagnostic::Diagnostic;
+pub use interpreter::Interpreter;
+pub use object::NativeFn;
+pub use value::{LoxValue, ObjectHandle};
+pub use vm::{LoxError, NativeContext, RuntimeError, TraceFrame};
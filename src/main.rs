@@ -1,21 +1,34 @@
-#![feature(is_some_and)]
-
 use anyhow::{anyhow, Result};
+use rslox::{Interpreter, LoxError};
 use std::env::args;
 use std::fs;
-use std::io::{stdin, stdout, BufRead, Write};
+use std::io::{stderr, stdin, stdout, BufRead, IsTerminal, Write};
+
+const USAGE: &str = "Usage: rslox [--error-format=human|json] [path]\n";
 
-pub mod chunk;
-pub mod compiler;
-pub mod scanner;
-pub mod token;
-pub mod value;
-pub mod vm;
+/// How errors in Lox programs are reported
+#[derive(Clone, Copy, PartialEq)]
+enum ErrorFormat {
+    // rustc style, colored if stderr is a terminal
+    Human,
+    // one JSON object per line, for editors
+    Json,
+}
 
-use vm::VM;
+/// Print the diagnostics for an error to stderr in the given format. `file` is the name the
+/// source is reported under.
+fn report(file: &str, source: &str, error: &LoxError, format: ErrorFormat) {
+    let color = stderr().is_terminal();
+    for diagnostic in error.diagnostics() {
+        match format {
+            ErrorFormat::Human => eprintln!("{}", diagnostic.render(file, source, color)),
+            ErrorFormat::Json => eprintln!("{}", diagnostic.to_json(file, source)),
+        }
+    }
+}
 
-fn repl() -> Result<()> {
-    let vm = VM::init();
+fn repl(format: ErrorFormat) -> Result<()> {
+    let mut interpreter = Interpreter::init();
 
     loop {
         // acquire lock on stdout, print our little prompt
@@ -25,29 +38,52 @@ fn repl() -> Result<()> {
 
         let mut stdin_handle = stdin().lock();
         let mut buffer = String::new();
-        stdin_handle.read_line(&mut buffer)?;
-        vm.interpret(&buffer);
+        if stdin_handle.read_line(&mut buffer)? == 0 {
+            // end of input, e.g. ctrl-D
+            writeln!(stdout_lock)?;
+            return Ok(());
+        }
+
+        // an error only ends the current line, the session carries on
+        if let Err(error) = interpreter.eval(&buffer) {
+            report("<stdin>", &buffer, &error, format);
+        }
     }
 }
 
-fn run_file(filename: &String) -> Result<()> {
+fn run_file(filename: &str, format: ErrorFormat) -> Result<()> {
     let source = fs::read_to_string(filename)?;
-    let vm = VM::init();
-    vm.interpret(&source);
-    Ok(())
+    let mut interpreter = Interpreter::init();
+
+    match interpreter.eval(&source) {
+        Ok(_) => Ok(()),
+        Err(error) => {
+            report(filename, &source, &error, format);
+            Err(anyhow!(match error {
+                LoxError::Compile(_) => "Compilation failed",
+                LoxError::Runtime(_) => "Runtime error",
+            }))
+        }
+    }
 }
 
 fn main() -> Result<()> {
     env_logger::init();
 
-    let arguments: Vec<String> = args().collect();
-
-    match arguments.len() {
-        1 => repl(),
-        2 => run_file(&arguments[1]),
-        _ => {
-            // log::error!("Usage: rslox [path]\n");
-            Err(anyhow!("Usage: rslox [path]\n"))
+    let mut format = ErrorFormat::Human;
+    let mut paths = vec![];
+    for argument in args().skip(1) {
+        match argument.strip_prefix("--error-format=") {
+            Some("human") => format = ErrorFormat::Human,
+            Some("json") => format = ErrorFormat::Json,
+            Some(other) => return Err(anyhow!("Unknown error format '{}'.\n{}", other, USAGE)),
+            None => paths.push(argument),
         }
     }
+
+    match paths.as_slice() {
+        [] => repl(format),
+        [path] => run_file(path, format),
+        _ => Err(anyhow!(USAGE)),
+    }
 }
@@ -0,0 +1,313 @@
+use crate::chunk::OpCode;
+use crate::object::{Obj, ObjKind, ObjRef, ObjString, UpvalueLocation};
+use crate::value::Value;
+use log::{debug, log_enabled, Level};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::mem::size_of;
+
+/// How many bytes can be allocated before the first collection
+const FIRST_GC: usize = 1024 * 1024;
+
+/// After each collection, the next one happens once the heap has grown by this factor
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// Anything that holds onto heap objects from outside of the heap, like the VM's stack or the
+/// functions the compiler is still building. The garbage collector can only see objects which are
+/// reachable from one of these.
+pub(crate) trait Roots {
+    /// Mark every object held onto directly, using `Heap::mark_object` or `Heap::mark_value`
+    fn mark_roots(&self, heap: &mut Heap);
+}
+
+/// For when there's nothing outside of the heap worth keeping alive
+impl Roots for () {
+    fn mark_roots(&self, _heap: &mut Heap) {}
+}
+
+/// Owns every object allocated while compiling and running Lox code, and frees them again with a
+/// mark-sweep garbage collector.
+///
+/// The heap doesn't know what's in use by itself, so it never collects on its own initiative.
+/// Whoever holds the roots checks `should_collect` before allocating, marks its roots and then
+/// calls `collect`. Anything allocated but not yet reachable from a root when that happens is
+/// freed, so a new object has to be stored somewhere visible before the next allocation.
+///
+/// The heap also holds the string intern table: every string the VM sees is stored exactly once,
+/// so two strings with the same contents are always the same `ObjRef`. The table doesn't keep its
+/// strings alive, so strings which are no longer used are freed like any other object.
+pub struct Heap {
+    objects: Vec<ObjRef>,
+    strings: HashMap<String, ObjRef>,
+    // marked objects whose own references haven't been traced yet
+    gray_stack: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+}
+
+impl Heap {
+    pub fn init() -> Heap {
+        Heap {
+            objects: vec![],
+            strings: HashMap::new(),
+            gray_stack: vec![],
+            bytes_allocated: 0,
+            next_gc: FIRST_GC,
+        }
+    }
+
+    /// Get the interned string with the given contents, allocating it if this is the first time
+    /// we've seen it.
+    pub fn copy_string(&mut self, chars: &str) -> ObjRef {
+        match self.strings.get(chars) {
+            Some(interned) => *interned,
+            None => self.take_string(String::from(chars)),
+        }
+    }
+
+    /// Like `copy_string`, but for a `String` we already own, e.g. the result of a concatenation.
+    pub fn take_string(&mut self, chars: String) -> ObjRef {
+        if let Some(interned) = self.strings.get(&chars) {
+            return *interned;
+        }
+
+        let string = self.allocate(ObjKind::String(ObjString {
+            chars: chars.clone(),
+        }));
+        self.strings.insert(chars, string);
+        string
+    }
+
+    /// Find the interned string with the given contents, without allocating it if it's missing
+    pub fn find_string(&self, chars: &str) -> Option<ObjRef> {
+        self.strings.get(chars).copied()
+    }
+
+    /// Move `kind` onto the heap. The object is freed by the next `collect` unless it's reachable
+    /// from a root by then.
+    pub(crate) fn allocate(&mut self, kind: ObjKind) -> ObjRef {
+        let obj = Obj {
+            kind,
+            is_marked: Cell::new(false),
+        };
+        self.bytes_allocated += size_of_obj(&obj);
+
+        let obj = ObjRef::new(Box::new(obj));
+        self.objects.push(obj);
+        obj
+    }
+
+    /// How many objects are currently allocated
+    #[cfg(test)]
+    pub fn object_count(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// Roughly how many bytes the objects currently allocated are using
+    #[cfg(test)]
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    /// Whether enough has been allocated since the last collection that it's time for another.
+    /// With the `gc-stress` feature this is always true, to shake out objects which aren't
+    /// properly rooted.
+    pub fn should_collect(&self) -> bool {
+        cfg!(feature = "gc-stress") || self.bytes_allocated > self.next_gc
+    }
+
+    pub(crate) fn mark_value(&mut self, value: Value) {
+        if let Value::Obj(obj) = value {
+            self.mark_object(obj);
+        }
+    }
+
+    pub(crate) fn mark_object(&mut self, obj: ObjRef) {
+        if obj.is_marked.replace(true) {
+            return;
+        }
+        self.gray_stack.push(obj);
+    }
+
+    /// Free every object which isn't reachable from the roots marked since the last collection.
+    /// This is only for the crate itself, since any `ObjRef` held elsewhere could be left dangling.
+    pub(crate) fn collect(&mut self) {
+        let before = self.bytes_allocated;
+
+        self.trace_references();
+        // interned strings only survive if something else is still using them
+        self.strings.retain(|_, string| string.is_marked.get());
+        self.sweep();
+
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(FIRST_GC);
+
+        if log_enabled!(Level::Debug) {
+            debug!(
+                "collected {} bytes (from {} to {}), next at {}",
+                before - self.bytes_allocated,
+                before,
+                self.bytes_allocated,
+                self.next_gc
+            );
+        }
+    }
+
+    // private functions
+    fn trace_references(&mut self) {
+        while let Some(obj) = self.gray_stack.pop() {
+            self.blacken_object(obj);
+        }
+    }
+
+    /// Mark everything `obj` refers to
+    fn blacken_object(&mut self, obj: ObjRef) {
+        match &obj.kind {
+            ObjKind::String(_) => (),
+            ObjKind::Function(function) => {
+                if let Some(name) = function.name {
+                    self.mark_object(name);
+                }
+                for constant in &function.chunk.constants {
+                    self.mark_value(*constant);
+                }
+            }
+            ObjKind::Closure(closure) => {
+                self.mark_object(closure.function);
+                for upvalue in &closure.upvalues {
+                    self.mark_object(*upvalue);
+                }
+            }
+            ObjKind::Upvalue(upvalue) => {
+                // an open upvalue's variable is on the stack, which is a root anyway
+                if let UpvalueLocation::Closed(value) = *upvalue.location.borrow() {
+                    self.mark_value(value);
+                }
+            }
+            ObjKind::Class(class) => {
+                self.mark_object(class.name);
+                for (name, method) in class.methods.borrow().iter() {
+                    self.mark_object(*name);
+                    self.mark_value(*method);
+                }
+            }
+            ObjKind::Instance(instance) => {
+                self.mark_object(instance.class);
+                for (name, field) in instance.fields.borrow().iter() {
+                    self.mark_object(*name);
+                    self.mark_value(*field);
+                }
+            }
+            ObjKind::BoundMethod(bound) => {
+                self.mark_value(bound.receiver);
+                self.mark_object(bound.method);
+            }
+            ObjKind::Native(native) => self.mark_object(native.name),
+        }
+    }
+
+    fn sweep(&mut self) {
+        let mut freed = 0;
+
+        self.objects.retain(|obj| {
+            if obj.is_marked.replace(false) {
+                return true;
+            }
+
+            freed += size_of_obj(obj);
+            // SAFETY: nothing reachable refers to this object, and it's dropped from `objects`
+            // here so it can't be freed twice
+            unsafe { obj.free() };
+            false
+        });
+
+        self.bytes_allocated -= freed;
+    }
+}
+
+impl Drop for Heap {
+    fn drop(&mut self) {
+        for obj in self.objects.drain(..) {
+            // SAFETY: every object in `objects` was allocated by this heap and is freed exactly once
+            unsafe { obj.free() };
+        }
+    }
+}
+
+/// Roughly how much memory an object takes up, counting whatever it owns outside of the heap.
+/// Only the parts which can't change once the object is allocated are counted, so that the same
+/// size is subtracted again when it's freed.
+fn size_of_obj(obj: &Obj) -> usize {
+    let owned = match &obj.kind {
+        ObjKind::String(string) => string.chars.capacity(),
+        ObjKind::Function(function) => {
+            function.chunk.code.capacity() * size_of::<OpCode>()
+                + function.chunk.constants.capacity() * size_of::<Value>()
+        }
+        ObjKind::Closure(closure) => closure.upvalues.capacity() * size_of::<ObjRef>(),
+        _ => 0,
+    };
+    size_of::<Obj>() + owned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{ObjClass, ObjInstance};
+
+    #[test]
+    fn strings_are_interned() {
+        let mut heap = Heap::init();
+        let a = heap.copy_string("hello");
+        let b = heap.take_string(String::from("hello"));
+        let c = heap.copy_string("world");
+
+        assert!(a == b);
+        assert!(a != c);
+        assert_eq!(heap.objects.len(), 2);
+    }
+
+    #[test]
+    fn unreachable_objects_are_freed() {
+        let mut heap = Heap::init();
+        let kept = heap.copy_string("kept");
+        heap.copy_string("garbage");
+        let allocated = heap.bytes_allocated;
+
+        heap.mark_object(kept);
+        heap.collect();
+
+        assert_eq!(heap.objects, vec![kept]);
+        assert!(heap.bytes_allocated < allocated);
+        // the intern table doesn't keep "garbage" alive, and forgets about it once it's freed
+        assert_eq!(heap.strings.len(), 1);
+        assert!(heap.copy_string("kept") == kept);
+
+        // marks only last for a single collection
+        heap.collect();
+        assert!(heap.objects.is_empty());
+        assert_eq!(heap.bytes_allocated, 0);
+    }
+
+    #[test]
+    fn references_are_traced() {
+        let mut heap = Heap::init();
+        let name = heap.copy_string("Point");
+        let class = heap.allocate(ObjKind::Class(ObjClass::init(name)));
+        let instance = heap.allocate(ObjKind::Instance(ObjInstance::init(class)));
+        let field = heap.copy_string("x");
+        let value = heap.copy_string("value");
+        instance
+            .as_instance()
+            .unwrap()
+            .fields
+            .borrow_mut()
+            .insert(field, Value::Obj(value));
+        heap.copy_string("garbage");
+
+        heap.mark_object(instance);
+        heap.collect();
+
+        assert_eq!(heap.objects.len(), 5);
+        assert!(!heap.strings.contains_key("garbage"));
+    }
+}
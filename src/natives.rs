@@ -0,0 +1,32 @@
+//! The native functions every `VM` starts out with
+
+use crate::value::LoxValue;
+use crate::vm::NativeContext;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The number of seconds since the Unix epoch, for timing things
+pub fn clock(_context: &mut NativeContext, _args: &[LoxValue]) -> Result<LoxValue, String> {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => Ok(LoxValue::Number(elapsed.as_secs_f64())),
+        Err(_) => Err(String::from("System clock is set before the Unix epoch.")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::VM;
+
+    #[test]
+    fn clock_moves_forwards() {
+        let mut vm = VM::init();
+        let (Ok(LoxValue::Number(before)), Ok(LoxValue::Number(after))) = (
+            clock(&mut NativeContext::init(&mut vm), &[]),
+            clock(&mut NativeContext::init(&mut vm), &[]),
+        ) else {
+            panic!("clock should return a number");
+        };
+        assert!(before > 0.0);
+        assert!(after >= before);
+    }
+}
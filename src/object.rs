@@ -0,0 +1,264 @@
+use crate::chunk::Chunk;
+use crate::value::{LoxValue, Value};
+use crate::vm::NativeContext;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ptr::NonNull;
+
+/// A heap-allocated Lox object. Objects are owned by the `Heap` that created them and are only
+/// ever handed out as `ObjRef`s.
+pub struct Obj {
+    pub kind: ObjKind,
+    // set by the garbage collector for every object it can reach, and cleared again once it has
+    // swept away the ones it couldn't
+    pub(crate) is_marked: Cell<bool>,
+}
+
+pub enum ObjKind {
+    String(ObjString),
+    Function(ObjFunction),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+    Native(ObjNative),
+}
+
+pub struct ObjString {
+    pub chars: String,
+}
+
+/// A compiled Lox function, including the top-level "function" wrapping a whole script
+pub struct ObjFunction {
+    pub arity: usize,
+    pub chunk: Chunk,
+    // `None` for the top-level script
+    pub name: Option<ObjRef>,
+    // which variables from the surrounding code a closure over this function has to capture
+    pub upvalues: Vec<UpvalueDescriptor>,
+}
+
+impl ObjFunction {
+    pub fn init(name: Option<ObjRef>) -> ObjFunction {
+        ObjFunction {
+            arity: 0,
+            chunk: Chunk::init(),
+            name,
+            upvalues: vec![],
+        }
+    }
+}
+
+/// Where `OpClosure` should find a variable to capture
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UpvalueDescriptor {
+    // either a local slot in the enclosing function's frame, or the index of one of the
+    // enclosing closure's own upvalues
+    pub index: usize,
+    pub is_local: bool,
+}
+
+/// A function together with the variables it has captured from the code surrounding it. Every
+/// function is wrapped in a closure at runtime, even if it doesn't capture anything.
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+/// A variable captured by a closure
+pub struct ObjUpvalue {
+    pub location: RefCell<UpvalueLocation>,
+}
+
+pub enum UpvalueLocation {
+    // the variable is still alive on the VM's stack, at this index
+    Open(usize),
+    // the variable has gone out of scope, so the upvalue holds onto it by itself
+    Closed(Value),
+}
+
+/// A Lox class. Calling it creates a new instance.
+pub struct ObjClass {
+    pub name: ObjRef,
+    // closures keyed by their (interned) name. Methods are added one at a time by `OpMethod`
+    // after the class itself has been created, hence the `RefCell`.
+    pub methods: RefCell<HashMap<ObjRef, Value>>,
+}
+
+impl ObjClass {
+    pub fn init(name: ObjRef) -> ObjClass {
+        ObjClass {
+            name,
+            methods: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+pub struct ObjInstance {
+    pub class: ObjRef,
+    // fields keyed by their (interned) name. Any field can be added to any instance at any time.
+    pub fields: RefCell<HashMap<ObjRef, Value>>,
+}
+
+impl ObjInstance {
+    pub fn init(class: ObjRef) -> ObjInstance {
+        ObjInstance {
+            class,
+            fields: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+/// A method which has been looked up on an instance, and so remembers the instance to use as
+/// `this` when it's eventually called
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+/// A Rust function which can be called from Lox. The arguments have already been checked against
+/// the native's arity. Returning an error message raises a runtime error at the call.
+///
+/// This is a closure rather than a plain `fn`, so that it can hold onto state from the host.
+pub type NativeFn = Box<dyn Fn(&mut NativeContext<'_>, &[LoxValue]) -> Result<LoxValue, String>>;
+
+/// A function implemented in Rust by the host, see `VM::define_native`
+pub struct ObjNative {
+    pub name: ObjRef,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl fmt::Display for ObjFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name {
+            Some(name) => f.pad(&format!("<fn {}>", name)),
+            None => f.pad("<script>"),
+        }
+    }
+}
+
+impl fmt::Display for Obj {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ObjKind::String(string) => f.pad(&string.chars),
+            ObjKind::Function(function) => function.fmt(f),
+            ObjKind::Closure(closure) => closure.function.fmt(f),
+            ObjKind::Upvalue(_) => f.pad("upvalue"),
+            ObjKind::Class(class) => class.name.fmt(f),
+            ObjKind::Instance(instance) => f.pad(&format!("{} instance", instance.class)),
+            ObjKind::BoundMethod(bound) => bound.method.fmt(f),
+            ObjKind::Native(_) => f.pad("<native fn>"),
+        }
+    }
+}
+
+/// A handle to an object living on the `Heap`.
+///
+/// This is a thin wrapper around a raw pointer, which keeps `Value` small and `Copy`. Two
+/// `ObjRef`s are equal only if they point at the very same object, which (thanks to string
+/// interning) is all we need to compare strings.
+#[derive(Clone, Copy)]
+pub(crate) struct ObjRef(NonNull<Obj>);
+
+impl ObjRef {
+    /// Take ownership of a freshly boxed object. Only the `Heap` should call this, and it is
+    /// responsible for eventually handing the pointer back to `free`.
+    pub(crate) fn new(obj: Box<Obj>) -> ObjRef {
+        ObjRef(NonNull::from(Box::leak(obj)))
+    }
+
+    /// Release the object this handle points at.
+    ///
+    /// # Safety
+    ///
+    /// The object must have been created with `ObjRef::new`, must not have been freed already
+    /// and must not be used through any other handle afterwards.
+    pub(crate) unsafe fn free(self) {
+        drop(Box::from_raw(self.0.as_ptr()));
+    }
+
+    pub fn as_string(&self) -> Option<&ObjString> {
+        match &self.kind {
+            ObjKind::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_function(&self) -> Option<&ObjFunction> {
+        match &self.kind {
+            ObjKind::Function(function) => Some(function),
+            _ => None,
+        }
+    }
+
+    pub fn as_closure(&self) -> Option<&ObjClosure> {
+        match &self.kind {
+            ObjKind::Closure(closure) => Some(closure),
+            _ => None,
+        }
+    }
+
+    pub fn as_upvalue(&self) -> Option<&ObjUpvalue> {
+        match &self.kind {
+            ObjKind::Upvalue(upvalue) => Some(upvalue),
+            _ => None,
+        }
+    }
+
+    pub fn as_class(&self) -> Option<&ObjClass> {
+        match &self.kind {
+            ObjKind::Class(class) => Some(class),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance(&self) -> Option<&ObjInstance> {
+        match &self.kind {
+            ObjKind::Instance(instance) => Some(instance),
+            _ => None,
+        }
+    }
+}
+
+impl std::ops::Deref for ObjRef {
+    type Target = Obj;
+
+    fn deref(&self) -> &Obj {
+        // SAFETY: the `Heap` keeps every object alive until it frees it, and it only frees objects
+        // that are no longer reachable from any `Value`
+        unsafe { self.0.as_ref() }
+    }
+}
+
+impl PartialEq for ObjRef {
+    fn eq(&self, other: &ObjRef) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl Eq for ObjRef {}
+
+impl Hash for ObjRef {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl fmt::Debug for ObjRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ObjKind::String(string) => write!(f, "{:?}", string.chars),
+            _ => write!(f, "{}", self),
+        }
+    }
+}
+
+impl fmt::Display for ObjRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
@@ -1,29 +1,74 @@
-use crate::token::{check_keyword, Token, TokenInfo};
+use crate::token::{check_keyword, Span, Token, TokenInfo};
 use log::{debug, log_enabled, Level};
-use std::cell::Cell;
 use std::iter::Peekable;
 use std::str::Chars;
 
+/// Turns source code into tokens, by iterating over it. The iterator ends after the `EOF` token.
 pub struct Scanner<'a> {
-    line: Cell<i32>,
+    source: &'a str,
     chars: Peekable<Chars<'a>>,
+    // the byte offset of the next character, and its (1-based) line and column
+    current: usize,
+    line: i32,
+    column: usize,
+    // where the token currently being scanned started
+    start: Span,
+    // for each interpolated expression we're inside, how many of its braces are still open. The
+    // innermost expression is last.
+    interpolations: Vec<usize>,
+    // whether whitespace and comments are yielded as tokens too
+    trivia: bool,
+    // whether the `EOF` token has been yielded
+    finished: bool,
 }
 
 impl<'a> Scanner<'a> {
-    pub fn init(source: &str) -> Scanner {
+    /// A scanner for the tokens that mean something to the parser, leaving out whitespace and
+    /// comments
+    pub fn init(source: &str) -> Scanner<'_> {
         Scanner {
+            source,
             chars: source.chars().peekable(),
-            line: Cell::new(0),
+            current: 0,
+            line: 1,
+            column: 1,
+            start: Span::default(),
+            interpolations: vec![],
+            trivia: false,
+            finished: false,
         }
     }
 
-    pub fn scan_token(&mut self) -> TokenInfo {
-        self.skip_whitespace();
+    /// A scanner for every piece of the source, including whitespace and comments, so that the
+    /// lexemes of its tokens add up to the whole source
+    pub fn with_trivia(source: &str) -> Scanner<'_> {
+        Scanner {
+            trivia: true,
+            ..Scanner::init(source)
+        }
+    }
+
+    /// The code being scanned
+    pub fn source(&self) -> &'a str {
+        self.source
+    }
+
+    fn scan_token(&mut self) -> TokenInfo<'a> {
+        self.start = Span {
+            start: self.current,
+            end: self.current,
+            line: self.line,
+            column: self.column,
+        };
 
         if self.is_at_end() {
             return self.create_token(Token::EOF);
         }
 
+        if self.peek_satisfies(is_whitespace) {
+            return self.whitespace();
+        }
+
         if self.peek_satisfies(|c| c.is_alphabetic()) {
             return self.identifier();
         }
@@ -32,12 +77,28 @@ impl<'a> Scanner<'a> {
             return self.number();
         }
 
-        if let Some(c) = self.chars.next() {
+        if let Some(c) = self.advance() {
             return match c {
                 '(' => self.create_token(Token::LeftParen),
                 ')' => self.create_token(Token::RightParen),
-                '{' => self.create_token(Token::LeftBrace),
-                '}' => self.create_token(Token::RightBrace),
+                '{' => {
+                    if let Some(open_braces) = self.interpolations.last_mut() {
+                        *open_braces += 1;
+                    }
+                    self.create_token(Token::LeftBrace)
+                }
+                '}' => match self.interpolations.last_mut() {
+                    // this brace closes an interpolated expression, so the string carries on
+                    Some(0) => {
+                        self.interpolations.pop();
+                        self.string(true)
+                    }
+                    Some(open_braces) => {
+                        *open_braces -= 1;
+                        self.create_token(Token::RightBrace)
+                    }
+                    None => self.create_token(Token::RightBrace),
+                },
                 ';' => self.create_token(Token::Semicolon),
                 ',' => self.create_token(Token::Comma),
                 '.' => self.create_token(Token::Dot),
@@ -47,8 +108,17 @@ impl<'a> Scanner<'a> {
                     if self.peek_eq('/') {
                         // consume that "/"
                         self.advance();
-                        // when we get "//" that's the start of a comment!
-                        return self.comment();
+                        // when we get "//" that's the start of a comment! "///" starts a doc
+                        // comment, unless it's the start of a longer line of slashes.
+                        if self.peek_eq('/') && self.peek_nth(1) != Some('/') {
+                            self.advance();
+                            let doc = self.rest_of_line();
+                            return self.create_token(Token::DocComment(doc));
+                        }
+                        let comment = self.rest_of_line();
+                        return self.create_token(Token::Comment(comment));
+                    } else if self.matches('*') {
+                        return self.block_comment();
                     } else {
                         return self.create_token(Token::Slash);
                     }
@@ -82,12 +152,12 @@ impl<'a> Scanner<'a> {
                         false => Token::Greater,
                     })
                 }
-                '"' => self.string(),
-                _ => todo!(),
+                '"' => self.string(false),
+                _ => self.error_token(&format!("Unexpected character '{}'.", c)),
             };
         }
 
-        self.create_token(Token::Error)
+        self.create_token(Token::EOF)
     }
 
     /// Run a 'debug scan' which:
@@ -97,19 +167,13 @@ impl<'a> Scanner<'a> {
         if log_enabled!(Level::Debug) {
             let mut line = -1;
             debug!("scanning tokens...");
-            loop {
-                let token_info = self.scan_token();
-
-                if token_info.line != line {
-                    debug!("{:04} {:?}", token_info.line, token_info.token);
-                    line = token_info.line;
+            for token_info in self.by_ref() {
+                if token_info.span.line != line {
+                    debug!("{:04} {:?}", token_info.span.line, token_info.token);
+                    line = token_info.span.line;
                 } else {
                     debug!("   | {:?}", token_info.token);
                 }
-
-                if token_info.token == Token::EOF {
-                    break;
-                }
             }
         }
     }
@@ -125,9 +189,18 @@ impl<'a> Scanner<'a> {
         false
     }
 
-    // consume one character
-    fn advance(&mut self) {
-        self.chars.next();
+    /// Consume one character, keeping track of where we are in the source
+    fn advance(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        self.current += c.len_utf8();
+
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
     }
 
     // one character of lookahead
@@ -135,38 +208,33 @@ impl<'a> Scanner<'a> {
         self.chars.peek().copied()
     }
 
+    // lookahead past the next character, with `peek_nth(0)` being the same as `peek`
+    fn peek_nth(&self, n: usize) -> Option<char> {
+        self.source[self.current..].chars().nth(n)
+    }
+
     fn peek_eq(&mut self, c: char) -> bool {
-        self.chars.peek().map_or(false, |p| *p == c)
+        self.chars.peek().is_some_and(|p| *p == c)
     }
 
     /// Check that the next character matches a supplied predicate, without needing to pull it out
     /// or do an `unwrap`.
     fn peek_satisfies(&mut self, test: fn(char) -> bool) -> bool {
-        self.chars.peek().map_or(false, |c| test(*c))
+        self.chars.peek().is_some_and(|c| test(*c))
     }
 
-    fn skip_whitespace(&mut self) {
-        while let Some(c) = self.peek() {
-            match c {
-                ' ' | '\r' | '\t' => {
-                    self.advance();
-                }
-                '\n' => {
-                    self.advance();
-                    self.line.set(self.line.get() + 1);
-                }
-                _ => {
-                    break;
-                }
-            }
+    fn whitespace(&mut self) -> TokenInfo<'a> {
+        while self.peek_satisfies(is_whitespace) {
+            self.advance();
         }
+        self.create_token(Token::Whitespace)
     }
 
-    fn identifier(&mut self) -> TokenInfo {
+    fn identifier(&mut self) -> TokenInfo<'a> {
         let mut parsed_identifier = String::new();
 
         while self.peek_satisfies(|c| c.is_alphabetic() || c.is_ascii_digit()) {
-            parsed_identifier.push(self.chars.next().unwrap());
+            parsed_identifier.extend(self.advance());
         }
 
         self.create_token(
@@ -174,49 +242,161 @@ impl<'a> Scanner<'a> {
         )
     }
 
-    fn number(&mut self) -> TokenInfo {
-        let mut digits = String::new();
-
-        while self.peek_satisfies(|c| c.is_ascii_digit()) {
-            digits.push(self.chars.next().unwrap());
+    /// Scan a number literal: either decimal, with an optional fraction and exponent like
+    /// `1_000.5e-3`, or a whole number in hex (`0xFF`) or binary (`0b1010`). Digits can be
+    /// separated by `_`.
+    fn number(&mut self) -> TokenInfo<'a> {
+        let radix = match (self.peek(), self.peek_nth(1)) {
+            (Some('0'), Some('x')) => 16,
+            (Some('0'), Some('b')) => 2,
+            _ => 10,
+        };
+        if radix != 10 {
+            self.advance();
+            self.advance();
         }
+        let digits_start = self.current;
+        let has_digits = self.digits(radix);
 
-        if self.peek_eq('.') {
-            digits.push(self.chars.next().unwrap());
+        if radix == 10 {
+            // a `.` that isn't followed by a digit is left alone, so that `1.foo` is a property
+            if self.peek_eq('.') && self.peek_nth(1).is_some_and(|c| c.is_ascii_digit()) {
+                self.advance();
+                self.digits(10);
+            }
+            let exponent = match (self.peek(), self.peek_nth(1), self.peek_nth(2)) {
+                (Some('e' | 'E'), Some('+' | '-'), Some(c)) => c.is_ascii_digit(),
+                (Some('e' | 'E'), Some(c), _) => c.is_ascii_digit(),
+                _ => false,
+            };
+            if exponent {
+                self.advance();
+                self.matches('+');
+                self.matches('-');
+                self.digits(10);
+            }
         }
 
-        while self.peek_satisfies(|c| c.is_ascii_digit()) {
-            digits.push(self.chars.next().unwrap());
+        // anything else that could be part of a word means this isn't a number we understand,
+        // like `0b12`, `1__0` or `2e`
+        if !has_digits || self.peek_satisfies(|c| c.is_alphanumeric() || c == '_') {
+            while self.peek_satisfies(|c| c.is_alphanumeric() || c == '_') {
+                self.advance();
+            }
+            return self.invalid_number();
         }
 
-        let num: f64 = digits.parse().unwrap();
+        let digits = self.source[digits_start..self.current].replace('_', "");
+        let value = if radix == 10 {
+            match digits.parse() {
+                Ok(value) => value,
+                Err(_) => return self.invalid_number(),
+            }
+        } else {
+            digits.chars().fold(0.0, |value, c| {
+                value * radix as f64 + c.to_digit(radix).unwrap_or(0) as f64
+            })
+        };
 
-        self.create_token(Token::Number(num))
+        self.create_token(Token::Number(value))
     }
 
-    fn string(&mut self) -> TokenInfo {
-        let mut parsed_string = String::new();
+    fn invalid_number(&self) -> TokenInfo<'a> {
+        let lexeme = &self.source[self.start.start..self.current];
+        self.error_token(&format!("Invalid number literal '{}'.", lexeme))
+    }
 
-        while let Some(c) = self.peek() {
-            if c == '"' || self.is_at_end() {
-                break;
-            }
-            if c == '\n' {
-                self.line.set(self.line.get() + 1);
+    /// Consume a run of digits in the given radix, with single `_`s allowed between them. Returns
+    /// whether there were any digits.
+    fn digits(&mut self, radix: u32) -> bool {
+        let mut has_digits = false;
+        loop {
+            match self.peek() {
+                Some(c) if c.is_digit(radix) => {}
+                Some('_') if has_digits && self.peek_nth(1).is_some_and(|c| c.is_digit(radix)) => {}
+                _ => return has_digits,
             }
-            parsed_string.push(c);
             self.advance();
+            has_digits = true;
         }
+    }
 
-        if self.is_at_end() {
-            return self.create_token(Token::Error);
+    /// Scan a string literal, up to its closing quote or the `${` of an interpolated expression.
+    /// `continued` is true when picking the string up again after an interpolated expression.
+    fn string(&mut self, continued: bool) -> TokenInfo<'a> {
+        let mut parsed_string = String::new();
+        // after a bad escape sequence we still scan to the end of the string, so that the rest of
+        // it isn't mistaken for code
+        let mut error = None;
+
+        let interpolated = loop {
+            match self.advance() {
+                None => return self.error_token("Unterminated string."),
+                Some('"') => break false,
+                Some('$') if self.peek_eq('{') => {
+                    self.advance();
+                    self.interpolations.push(0);
+                    break true;
+                }
+                Some('\\') => match self.escape() {
+                    Ok(c) => parsed_string.push(c),
+                    Err(message) => {
+                        error.get_or_insert(message);
+                    }
+                },
+                Some(c) => parsed_string.push(c),
+            }
+        };
+
+        if let Some(message) = error {
+            return self.error_token(&message);
+        }
+
+        self.create_token(match (interpolated, continued) {
+            (true, _) => Token::Interpolation(parsed_string),
+            (false, true) => Token::InterpolationEnd(parsed_string),
+            (false, false) => Token::String(parsed_string),
+        })
+    }
+
+    /// Scan the rest of an escape sequence in a string, after its backslash
+    fn escape(&mut self) -> Result<char, String> {
+        match self.advance() {
+            Some('n') => Ok('\n'),
+            Some('t') => Ok('\t'),
+            Some('r') => Ok('\r'),
+            Some('"') => Ok('"'),
+            Some('\\') => Ok('\\'),
+            Some('$') => Ok('$'),
+            Some('u') => self.unicode_escape(),
+            Some(c) => Err(format!("Invalid escape sequence '\\{}'.", c.escape_debug())),
+            None => Err(String::from("Unterminated string.")),
+        }
+    }
+
+    /// Scan the `{...}` part of a `\u{...}` escape, which holds the hex code of a character
+    fn unicode_escape(&mut self) -> Result<char, String> {
+        let invalid = || String::from("Invalid unicode escape sequence.");
+
+        if !self.matches('{') {
+            return Err(invalid());
+        }
+        let mut digits = String::new();
+        while digits.len() < 6 && self.peek_satisfies(|c| c.is_ascii_hexdigit()) {
+            digits.extend(self.advance());
+        }
+        if digits.is_empty() || !self.matches('}') {
+            return Err(invalid());
         }
 
-        self.advance();
-        self.create_token(Token::String(parsed_string))
+        u32::from_str_radix(&digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(invalid)
     }
 
-    fn comment(&mut self) -> TokenInfo {
+    /// Consume everything up to the end of the line, for a line comment
+    fn rest_of_line(&mut self) -> String {
         let mut comment_string = String::new();
 
         while let Some(c) = self.peek() {
@@ -227,6 +407,34 @@ impl<'a> Scanner<'a> {
                 break;
             }
         }
+        comment_string
+    }
+
+    /// Scan a `/* ... */` comment, after its opening `/*`. Block comments nest, so that code
+    /// which already has some in it can be commented out.
+    fn block_comment(&mut self) -> TokenInfo<'a> {
+        let mut comment_string = String::new();
+        let mut depth = 1;
+
+        loop {
+            match self.advance() {
+                None => return self.error_token("Unterminated block comment."),
+                Some('*') if self.peek_eq('/') => {
+                    self.advance();
+                    depth -= 1;
+                    if depth == 0 {
+                        break;
+                    }
+                    comment_string.push_str("*/");
+                }
+                Some('/') if self.peek_eq('*') => {
+                    self.advance();
+                    depth += 1;
+                    comment_string.push_str("/*");
+                }
+                Some(c) => comment_string.push(c),
+            }
+        }
         self.create_token(Token::Comment(comment_string))
     }
 
@@ -234,22 +442,52 @@ impl<'a> Scanner<'a> {
         self.peek().is_none()
     }
 
-    fn create_token(&self, token: Token) -> TokenInfo {
+    fn error_token(&self, message: &str) -> TokenInfo<'a> {
+        self.create_token(Token::Error(String::from(message)))
+    }
+
+    fn create_token(&self, token: Token) -> TokenInfo<'a> {
         TokenInfo {
             token,
-            line: self.line.get(),
+            span: Span {
+                end: self.current,
+                ..self.start
+            },
+            lexeme: &self.source[self.start.start..self.current],
+        }
+    }
+}
+
+impl<'a> Iterator for Scanner<'a> {
+    type Item = TokenInfo<'a>;
+
+    fn next(&mut self) -> Option<TokenInfo<'a>> {
+        if self.finished {
+            return None;
+        }
+
+        loop {
+            let token_info = self.scan_token();
+            if token_info.token.is_trivia() && !self.trivia {
+                continue;
+            }
+            self.finished = token_info.token == Token::EOF;
+            return Some(token_info);
         }
     }
 }
 
+fn is_whitespace(c: char) -> bool {
+    matches!(c, ' ' | '\r' | '\t' | '\n')
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::token::Token;
 
     fn test_string_is_token(test_string: &str, token: Token) {
-        let mut scanner = Scanner::init(&test_string);
-        let token_info = scanner.scan_token();
+        let token_info = Scanner::init(test_string).next().unwrap();
 
         if token_info.token != token {
             println!("didnt match: {:?} and {:?}", token_info, token);
@@ -258,18 +496,21 @@ mod tests {
     }
 
     fn test_tokens(test_string: &str, tokens: Vec<Token>) {
-        let mut found_tokens: Vec<Token> = vec![];
+        let found_tokens: Vec<Token> = Scanner::init(test_string)
+            .map(|token_info| token_info.token)
+            .collect();
 
-        let mut scanner = Scanner::init(&test_string);
+        if found_tokens != tokens {
+            println!("didnt match: {:?} and {:?}", found_tokens, tokens);
+        }
+        assert!(found_tokens == tokens)
+    }
 
-        loop {
-            let token_info = scanner.scan_token();
-            found_tokens.push(token_info.token.clone());
+    fn test_tokens_with_trivia(test_string: &str, tokens: Vec<Token>) {
+        let found_tokens: Vec<Token> = Scanner::with_trivia(test_string)
+            .map(|token_info| token_info.token)
+            .collect();
 
-            if token_info.token == Token::EOF {
-                break;
-            }
-        }
         if found_tokens != tokens {
             println!("didnt match: {:?} and {:?}", found_tokens, tokens);
         }
@@ -279,9 +520,9 @@ mod tests {
     #[test]
     fn test_multiple_booleans() {
         let test_string = "true false";
-        let mut scanner = Scanner::init(&test_string);
-        assert!(scanner.scan_token().token == Token::True);
-        assert!(scanner.scan_token().token == Token::False);
+        let mut scanner = Scanner::init(test_string);
+        assert!(scanner.next().unwrap().token == Token::True);
+        assert!(scanner.next().unwrap().token == Token::False);
     }
 
     #[test]
@@ -328,6 +569,69 @@ mod tests {
         test_string_is_token("9", Token::Number(9.0));
     }
 
+    #[test]
+    fn number_formats() {
+        for (source, value) in [
+            ("123.25", 123.25),
+            ("1e3", 1000.0),
+            ("1E+3", 1000.0),
+            ("1e-9", 1e-9),
+            ("2.5e2", 250.0),
+            ("0xFF", 255.0),
+            ("0xdead_beef", 3735928559.0),
+            ("0b1010", 10.0),
+            ("1_000_000", 1_000_000.0),
+            ("1_0.0_1", 10.01),
+            ("0", 0.0),
+            ("007", 7.0),
+        ] {
+            test_string_is_token(source, Token::Number(value));
+        }
+    }
+
+    #[test]
+    fn a_dot_needs_a_digit_after_it_to_be_part_of_a_number() {
+        test_tokens(
+            "1.x 2. 3.e1",
+            vec![
+                Token::Number(1.0),
+                Token::Dot,
+                Token::Identifier(String::from("x")),
+                Token::Number(2.0),
+                Token::Dot,
+                Token::Number(3.0),
+                Token::Dot,
+                Token::Identifier(String::from("e1")),
+                Token::EOF,
+            ],
+        );
+        // an `e` that doesn't start an exponent isn't swallowed either
+        test_tokens(
+            "1e-x",
+            vec![
+                Token::Error(String::from("Invalid number literal '1e'.")),
+                Token::Minus,
+                Token::Identifier(String::from("x")),
+                Token::EOF,
+            ],
+        );
+    }
+
+    #[test]
+    fn errors_for_malformed_numbers() {
+        for source in [
+            "0x", "0xG1", "0b102", "1__0", "1_", "12abc", "1e", "0x_1", "1.5_",
+        ] {
+            test_tokens(
+                source,
+                vec![
+                    Token::Error(format!("Invalid number literal '{}'.", source)),
+                    Token::EOF,
+                ],
+            );
+        }
+    }
+
     #[test]
     fn punctuation() {
         test_string_is_token("(", Token::LeftParen);
@@ -388,13 +692,16 @@ mod tests {
 
     #[test]
     fn test_comment() {
-        test_tokens(
+        test_tokens_with_trivia(
             "1 + 2; // add those numbers!",
             vec![
                 Token::Number(1.0),
+                Token::Whitespace,
                 Token::Plus,
+                Token::Whitespace,
                 Token::Number(2.0),
                 Token::Semicolon,
+                Token::Whitespace,
                 Token::Comment(String::from(" add those numbers!")),
                 Token::EOF,
             ],
@@ -403,22 +710,269 @@ mod tests {
 
     #[test]
     fn test_comment_with_newline() {
-        test_tokens(
+        test_tokens_with_trivia(
             "1 + 2; // add those numbers!\nfoo",
             vec![
                 Token::Number(1.0),
+                Token::Whitespace,
                 Token::Plus,
+                Token::Whitespace,
                 Token::Number(2.0),
                 Token::Semicolon,
+                Token::Whitespace,
                 Token::Comment(String::from(" add those numbers!")),
+                Token::Whitespace,
+                Token::Identifier(String::from("foo")),
+                Token::EOF,
+            ],
+        );
+    }
+
+    #[test]
+    fn trivia_is_left_out_by_default() {
+        test_tokens(
+            "1 + 2; // add those numbers!\n/* and then */ foo /// docs",
+            vec![
+                Token::Number(1.0),
+                Token::Plus,
+                Token::Number(2.0),
+                Token::Semicolon,
                 Token::Identifier(String::from("foo")),
                 Token::EOF,
             ],
         );
     }
 
+    #[test]
+    fn trivia_tokens_cover_the_whole_source() {
+        let source = "  fun f(a) {\n\t/* hi */ return a; // done\n}\n/// end\n";
+        let lexemes: Vec<&str> = Scanner::with_trivia(source)
+            .map(|token_info| token_info.lexeme)
+            .collect();
+
+        assert_eq!(lexemes.concat(), source);
+        assert_eq!(lexemes[..4], ["  ", "fun", " ", "f"]);
+    }
+
+    #[test]
+    fn the_iterator_ends_after_eof() {
+        let mut scanner = Scanner::init("1");
+        assert_eq!(scanner.next().unwrap().token, Token::Number(1.0));
+        assert_eq!(scanner.next().unwrap().token, Token::EOF);
+        assert!(scanner.next().is_none());
+        assert!(scanner.next().is_none());
+    }
+
+    #[test]
+    fn block_comments() {
+        test_tokens_with_trivia(
+            "1 /* a /* nested */ comment */ / 2/**/",
+            vec![
+                Token::Number(1.0),
+                Token::Whitespace,
+                Token::Comment(String::from(" a /* nested */ comment ")),
+                Token::Whitespace,
+                Token::Slash,
+                Token::Whitespace,
+                Token::Number(2.0),
+                Token::Comment(String::from("")),
+                Token::EOF,
+            ],
+        );
+        test_tokens(
+            "/* one /* two */ still open *",
+            vec![
+                Token::Error(String::from("Unterminated block comment.")),
+                Token::EOF,
+            ],
+        );
+
+        // the lines inside the comment still count
+        let lines: Vec<i32> = Scanner::init("/* a\n\n b */ x\ny")
+            .map(|token_info| token_info.span.line)
+            .collect();
+        assert_eq!(lines, vec![3, 4, 4]);
+    }
+
+    #[test]
+    fn doc_comments() {
+        test_tokens_with_trivia(
+            "/// Adds things.\n///\n//// not docs\nfun add() {}",
+            vec![
+                Token::DocComment(String::from(" Adds things.")),
+                Token::Whitespace,
+                Token::DocComment(String::from("")),
+                Token::Whitespace,
+                Token::Comment(String::from("// not docs")),
+                Token::Whitespace,
+                Token::Fun,
+                Token::Whitespace,
+                Token::Identifier(String::from("add")),
+                Token::LeftParen,
+                Token::RightParen,
+                Token::Whitespace,
+                Token::LeftBrace,
+                Token::RightBrace,
+                Token::EOF,
+            ],
+        );
+    }
+
+    #[test]
+    fn tokens_have_spans() {
+        let source = "var café = \"a\nb\";\n  print café;";
+        let tokens: Vec<_> = Scanner::init(source)
+            .map(|token_info| {
+                let span = token_info.span;
+                (
+                    token_info.lexeme,
+                    span.start,
+                    span.end,
+                    span.line,
+                    span.column,
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                ("var", 0, 3, 1, 1),
+                ("café", 4, 9, 1, 5),
+                ("=", 10, 11, 1, 10),
+                ("\"a\nb\"", 12, 17, 1, 12),
+                (";", 17, 18, 2, 3),
+                ("print", 21, 26, 3, 3),
+                ("café", 27, 32, 3, 9),
+                (";", 32, 33, 3, 13),
+                ("", 33, 33, 3, 14),
+            ]
+        );
+    }
+
     #[test]
     fn error_for_unterminated_string() {
-        test_tokens("\"this never ends", vec![Token::Error, Token::EOF]);
+        test_tokens(
+            "\"this never ends",
+            vec![
+                Token::Error(String::from("Unterminated string.")),
+                Token::EOF,
+            ],
+        );
+    }
+
+    #[test]
+    fn error_for_unexpected_characters() {
+        test_tokens(
+            "a @ é #",
+            vec![
+                Token::Identifier(String::from("a")),
+                Token::Error(String::from("Unexpected character '@'.")),
+                Token::Identifier(String::from("é")),
+                Token::Error(String::from("Unexpected character '#'.")),
+                Token::EOF,
+            ],
+        );
+    }
+
+    #[test]
+    fn scanning_never_panics() {
+        // every ASCII character, plus a few multi-byte ones, in every position a token can start
+        let characters: String = (0..128u8).map(char::from).chain("é€😀".chars()).collect();
+        for c in characters.chars() {
+            for source in [
+                c.to_string(),
+                format!("a{}", c),
+                format!("1{}", c),
+                format!("1.{}", c),
+                format!("\"{}", c),
+                format!("//{}", c),
+            ] {
+                Scanner::with_trivia(&source).for_each(drop);
+            }
+        }
+    }
+
+    #[test]
+    fn escape_sequences() {
+        test_string_is_token(
+            r#""a\tb\nc \"d\" \\ \$ \u{e9}\u{1F600}\r""#,
+            Token::String(String::from("a\tb\nc \"d\" \\ $ é😀\r")),
+        );
+    }
+
+    #[test]
+    fn errors_for_bad_escape_sequences() {
+        for (source, message) in [
+            (r#""a\qb""#, "Invalid escape sequence '\\q'."),
+            (r#""\u00e9""#, "Invalid unicode escape sequence."),
+            (r#""\u{}""#, "Invalid unicode escape sequence."),
+            (r#""\u{e9""#, "Invalid unicode escape sequence."),
+            (r#""\u{1234567}""#, "Invalid unicode escape sequence."),
+            (r#""\u{D800}""#, "Invalid unicode escape sequence."),
+            (r#""\"#, "Unterminated string."),
+        ] {
+            test_tokens(
+                source,
+                vec![Token::Error(String::from(message)), Token::EOF],
+            );
+        }
+
+        // the rest of the string is skipped, rather than being scanned as code
+        test_tokens(
+            r#""\q \w" 1"#,
+            vec![
+                Token::Error(String::from("Invalid escape sequence '\\q'.")),
+                Token::Number(1.0),
+                Token::EOF,
+            ],
+        );
+    }
+
+    #[test]
+    fn interpolated_strings() {
+        test_tokens(
+            r#""Hello ${name}!""#,
+            vec![
+                Token::Interpolation(String::from("Hello ")),
+                Token::Identifier(String::from("name")),
+                Token::InterpolationEnd(String::from("!")),
+                Token::EOF,
+            ],
+        );
+
+        // braces inside the expression don't end it, and strings can be interpolated in turn
+        test_tokens(
+            r#""${a}${ f({}) } ${"<${b}>"}""#,
+            vec![
+                Token::Interpolation(String::from("")),
+                Token::Identifier(String::from("a")),
+                Token::Interpolation(String::from("")),
+                Token::Identifier(String::from("f")),
+                Token::LeftParen,
+                Token::LeftBrace,
+                Token::RightBrace,
+                Token::RightParen,
+                Token::Interpolation(String::from(" ")),
+                Token::Interpolation(String::from("<")),
+                Token::Identifier(String::from("b")),
+                Token::InterpolationEnd(String::from(">")),
+                Token::InterpolationEnd(String::from("")),
+                Token::EOF,
+            ],
+        );
+
+        // a lone `$` or `{` is just part of the string
+        test_string_is_token(r#""$ {} $""#, Token::String(String::from("$ {} $")));
+
+        test_tokens(
+            r#""${a} and then"#,
+            vec![
+                Token::Interpolation(String::from("")),
+                Token::Identifier(String::from("a")),
+                Token::Error(String::from("Unterminated string.")),
+                Token::EOF,
+            ],
+        );
     }
 }
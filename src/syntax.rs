@@ -0,0 +1,906 @@
+//! A lossless syntax tree, for tools like formatters that need to see the source exactly as it
+//! was written.
+//!
+//! This is a separate front end from the compiler's `Parser`, which goes straight to bytecode. It
+//! keeps every token the scanner produces, including whitespace and comments, so printing a tree
+//! gives back the source it was parsed from byte for byte. Code with errors in it still parses,
+//! with the parts that couldn't be understood put in `Error` nodes.
+
+use crate::compiler::{precedence_for_op, CompileError, Precedence};
+use crate::scanner::Scanner;
+use crate::token::{Token, TokenInfo};
+use std::fmt;
+
+/// What a node in the syntax tree is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyntaxKind {
+    // the whole source, which ends with the `EOF` token
+    Script,
+
+    // Declarations.
+    ClassDecl,
+    FunDecl,
+    Method,
+    ParamList,
+    VarDecl,
+
+    // Statements.
+    Block,
+    ExprStmt,
+    ForStmt,
+    IfStmt,
+    PrintStmt,
+    ReturnStmt,
+    WhileStmt,
+
+    // Expressions.
+    Assign,
+    Binary,
+    Call,
+    ArgList,
+    Get,
+    Grouping,
+    Interpolation,
+    Literal,
+    Super,
+    This,
+    Unary,
+    Variable,
+
+    // tokens that were skipped over while recovering from an error
+    Error,
+}
+
+/// A child of a node: either another node or a single token
+#[derive(Clone, Debug)]
+pub enum SyntaxElement<'a> {
+    Node(SyntaxNode<'a>),
+    Token(TokenInfo<'a>),
+}
+
+/// A node in the syntax tree. The whitespace and comments in front of a token belong to the
+/// outermost node that starts with that token, so a declaration holds the doc comments above it.
+#[derive(Clone, Debug)]
+pub struct SyntaxNode<'a> {
+    pub kind: SyntaxKind,
+    pub children: Vec<SyntaxElement<'a>>,
+}
+
+impl<'a> SyntaxNode<'a> {
+    /// The nodes directly under this one
+    pub fn child_nodes(&self) -> impl Iterator<Item = &SyntaxNode<'a>> {
+        self.children.iter().filter_map(|child| match child {
+            SyntaxElement::Node(node) => Some(node),
+            SyntaxElement::Token(_) => None,
+        })
+    }
+
+    /// Every token in this node, in source order
+    pub fn tokens(&self) -> Vec<&TokenInfo<'a>> {
+        let mut tokens = vec![];
+        self.collect_tokens(&mut tokens);
+        tokens
+    }
+
+    fn collect_tokens<'n>(&'n self, tokens: &mut Vec<&'n TokenInfo<'a>>) {
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => node.collect_tokens(tokens),
+                SyntaxElement::Token(token_info) => tokens.push(token_info),
+            }
+        }
+    }
+
+    /// The text of the `///` comments in front of this node, one line per comment, or `None` if
+    /// there aren't any
+    pub fn doc_comment(&self) -> Option<String> {
+        let lines: Vec<&str> = self
+            .children
+            .iter()
+            .map_while(|child| match child {
+                SyntaxElement::Token(token_info) if token_info.token.is_trivia() => {
+                    Some(&token_info.token)
+                }
+                _ => None,
+            })
+            .filter_map(|token| match token {
+                Token::DocComment(text) => Some(text.strip_prefix(' ').unwrap_or(text)),
+                _ => None,
+            })
+            .collect();
+
+        if lines.is_empty() {
+            None
+        } else {
+            Some(lines.join("\n"))
+        }
+    }
+}
+
+/// Printing a node gives back exactly the source it was parsed from
+impl fmt::Display for SyntaxNode<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for token_info in self.tokens() {
+            f.write_str(token_info.lexeme)?;
+        }
+        Ok(())
+    }
+}
+
+/// The result of parsing some source: its syntax tree, and everything wrong with it
+#[derive(Clone, Debug)]
+pub struct SyntaxTree<'a> {
+    pub root: SyntaxNode<'a>,
+    pub errors: Vec<CompileError>,
+}
+
+/// Parse `source` into a syntax tree. This never fails: errors are reported alongside the tree.
+pub fn parse(source: &str) -> SyntaxTree<'_> {
+    let mut parser = SyntaxParser::init(source);
+    parser.script();
+
+    let (kind, children) = parser.stack.pop().unwrap_or((SyntaxKind::Script, vec![]));
+    SyntaxTree {
+        root: SyntaxNode { kind, children },
+        errors: parser.errors,
+    }
+}
+
+struct SyntaxParser<'a> {
+    scanner: Scanner<'a>,
+    // the next token that means something to the parser
+    current: TokenInfo<'a>,
+    // the trivia in front of `current`, along with any tokens the scanner couldn't make sense of
+    pending: Vec<TokenInfo<'a>>,
+    // the nodes being built, innermost last
+    stack: Vec<(SyntaxKind, Vec<SyntaxElement<'a>>)>,
+    errors: Vec<CompileError>,
+    // like the compiler, we only report the first error in each declaration
+    panicking: bool,
+    // whether the last token added to the tree was a `;`, which is where synchronizing stops
+    after_semicolon: bool,
+}
+
+impl<'a> SyntaxParser<'a> {
+    fn init(source: &'a str) -> SyntaxParser<'a> {
+        let mut parser = SyntaxParser {
+            scanner: Scanner::with_trivia(source),
+            current: TokenInfo {
+                token: Token::EOF,
+                span: Default::default(),
+                lexeme: "",
+            },
+            pending: vec![],
+            stack: vec![],
+            errors: vec![],
+            panicking: false,
+            after_semicolon: false,
+        };
+        parser.advance();
+        parser
+    }
+
+    /// Move on to the next meaningful token, holding on to the trivia before it
+    fn advance(&mut self) {
+        // the scanner stops after the end of the file, where we stay
+        while let Some(token_info) = self.scanner.next() {
+            match token_info.token {
+                _ if token_info.token.is_trivia() => self.pending.push(token_info),
+                Token::Error(ref message) => {
+                    let message = message.clone();
+                    self.error_at(&token_info, &message);
+                    self.pending.push(token_info);
+                }
+                _ => {
+                    self.current = token_info;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn peek(&self) -> &Token {
+        &self.current.token
+    }
+
+    fn at(&self, token: &Token) -> bool {
+        *self.peek() == *token
+    }
+
+    fn at_end(&self) -> bool {
+        self.at(&Token::EOF)
+    }
+
+    /// Add the current token to the node being built, and move on. The end of the file is left
+    /// for `script` to add.
+    fn bump(&mut self) {
+        if self.at_end() {
+            return;
+        }
+        self.flush_pending();
+        let token_info = self.current.clone();
+        self.after_semicolon = token_info.token == Token::Semicolon;
+        self.push(SyntaxElement::Token(token_info));
+        self.advance();
+    }
+
+    fn flush_pending(&mut self) {
+        for token_info in std::mem::take(&mut self.pending) {
+            self.push(SyntaxElement::Token(token_info));
+        }
+    }
+
+    fn push(&mut self, element: SyntaxElement<'a>) {
+        if let Some((_, children)) = self.stack.last_mut() {
+            children.push(element);
+        }
+    }
+
+    fn expect(&mut self, token: Token, message: &str) {
+        if self.at(&token) {
+            self.bump();
+        } else {
+            self.error(message);
+        }
+    }
+
+    fn expect_identifier(&mut self, message: &str) {
+        if matches!(self.peek(), Token::Identifier(_)) {
+            self.bump();
+        } else {
+            self.error(message);
+        }
+    }
+
+    /// Start building a node. Any trivia in front of the next token goes in the new node.
+    fn start_node(&mut self, kind: SyntaxKind) {
+        self.stack.push((kind, vec![]));
+        self.flush_pending();
+    }
+
+    /// A place in the node being built, where a node can be started later on with
+    /// `start_node_at`. This is how the left operand of a binary expression ends up inside it.
+    fn checkpoint(&self) -> usize {
+        self.stack.last().map_or(0, |(_, children)| children.len())
+    }
+
+    fn start_node_at(&mut self, checkpoint: usize, kind: SyntaxKind) {
+        let children = match self.stack.last_mut() {
+            Some((_, children)) => children.split_off(checkpoint.min(children.len())),
+            None => vec![],
+        };
+        self.stack.push((kind, children));
+    }
+
+    fn finish_node(&mut self) {
+        if self.stack.len() > 1 {
+            if let Some((kind, children)) = self.stack.pop() {
+                self.push(SyntaxElement::Node(SyntaxNode { kind, children }));
+            }
+        }
+    }
+
+    fn error(&mut self, message: &str) {
+        let current = self.current.clone();
+        self.error_at(&current, message);
+    }
+
+    fn error_at(&mut self, token_info: &TokenInfo, message: &str) {
+        if self.panicking {
+            return;
+        }
+        self.panicking = true;
+
+        self.errors.push(CompileError {
+            span: token_info.span,
+            lexeme: match token_info.token {
+                Token::Error(_) => None,
+                _ => Some(String::from(token_info.lexeme)),
+            },
+            message: String::from(message),
+        });
+    }
+
+    /// Get out of panic mode by skipping tokens until what looks like the start of a statement,
+    /// putting them in an `Error` node
+    fn synchronize(&mut self) {
+        let checkpoint = self.checkpoint();
+        let mut skipped = false;
+        // an error which didn't stop the statement from being parsed, like an invalid assignment
+        // target, leaves nothing to skip
+        while !self.after_semicolon {
+            match self.peek() {
+                Token::EOF
+                | Token::RightBrace
+                | Token::Class
+                | Token::Fun
+                | Token::Var
+                | Token::For
+                | Token::If
+                | Token::While
+                | Token::Print
+                | Token::Return => break,
+                _ => {
+                    self.bump();
+                    skipped = true;
+                }
+            }
+        }
+
+        if skipped {
+            self.start_node_at(checkpoint, SyntaxKind::Error);
+            self.finish_node();
+        }
+
+        // only now, so that scanner errors in the tokens we skipped stay suppressed
+        self.panicking = false;
+    }
+
+    /// Make sure a loop over declarations moves forward, by skipping the current token if
+    /// nothing was parsed since `before`
+    fn skip_if_stuck(&mut self, before: usize) {
+        if self.current.span.start == before && !self.at_end() {
+            self.start_node(SyntaxKind::Error);
+            self.bump();
+            self.finish_node();
+        }
+    }
+
+    fn script(&mut self) {
+        // the trivia at the start of the file is left for the first declaration
+        self.stack.push((SyntaxKind::Script, vec![]));
+        while !self.at_end() {
+            let before = self.current.span.start;
+            self.declaration();
+            self.skip_if_stuck(before);
+        }
+        // the trivia at the end of the file, and the end of the file itself
+        self.flush_pending();
+        let eof = self.current.clone();
+        self.push(SyntaxElement::Token(eof));
+    }
+
+    fn declaration(&mut self) {
+        match self.peek() {
+            Token::Class => self.class_declaration(),
+            Token::Fun => self.fun_declaration(),
+            Token::Var => self.var_declaration(),
+            _ => self.statement(),
+        }
+
+        if self.panicking {
+            self.synchronize();
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.start_node(SyntaxKind::ClassDecl);
+        self.bump();
+        self.expect_identifier("Expect class name.");
+        if self.at(&Token::Less) {
+            self.bump();
+            self.expect_identifier("Expect superclass name.");
+        }
+
+        self.expect(Token::LeftBrace, "Expect '{' before class body.");
+        while !self.at(&Token::RightBrace) && !self.at_end() {
+            let before = self.current.span.start;
+            self.start_node(SyntaxKind::Method);
+            self.expect_identifier("Expect method name.");
+            self.function();
+            self.finish_node();
+            self.skip_if_stuck(before);
+        }
+        self.expect(Token::RightBrace, "Expect '}' after class body.");
+        self.finish_node();
+    }
+
+    fn fun_declaration(&mut self) {
+        self.start_node(SyntaxKind::FunDecl);
+        self.bump();
+        self.expect_identifier("Expect function name.");
+        self.function();
+        self.finish_node();
+    }
+
+    /// The parameters and body of a function or method
+    fn function(&mut self) {
+        self.start_node(SyntaxKind::ParamList);
+        self.expect(Token::LeftParen, "Expect '(' after function name.");
+        if !self.at(&Token::RightParen) {
+            loop {
+                self.expect_identifier("Expect parameter name.");
+                if !self.at(&Token::Comma) {
+                    break;
+                }
+                self.bump();
+            }
+        }
+        self.expect(Token::RightParen, "Expect ')' after parameters.");
+        self.finish_node();
+
+        self.block("Expect '{' before function body.");
+    }
+
+    fn var_declaration(&mut self) {
+        self.start_node(SyntaxKind::VarDecl);
+        self.bump();
+        self.expect_identifier("Expect variable name.");
+        if self.at(&Token::Equal) {
+            self.bump();
+            self.expression();
+        }
+        self.expect(Token::Semicolon, "Expect ';' after variable declaration.");
+        self.finish_node();
+    }
+
+    fn statement(&mut self) {
+        match self.peek() {
+            Token::Print => {
+                self.start_node(SyntaxKind::PrintStmt);
+                self.bump();
+                self.expression();
+                self.expect(Token::Semicolon, "Expect ';' after value.");
+                self.finish_node();
+            }
+            Token::Return => {
+                self.start_node(SyntaxKind::ReturnStmt);
+                self.bump();
+                if !self.at(&Token::Semicolon) {
+                    self.expression();
+                }
+                self.expect(Token::Semicolon, "Expect ';' after return value.");
+                self.finish_node();
+            }
+            Token::If => {
+                self.start_node(SyntaxKind::IfStmt);
+                self.bump();
+                self.expect(Token::LeftParen, "Expect '(' after 'if'.");
+                self.expression();
+                self.expect(Token::RightParen, "Expect ')' after condition.");
+                self.statement();
+                if self.at(&Token::Else) {
+                    self.bump();
+                    self.statement();
+                }
+                self.finish_node();
+            }
+            Token::While => {
+                self.start_node(SyntaxKind::WhileStmt);
+                self.bump();
+                self.expect(Token::LeftParen, "Expect '(' after 'while'.");
+                self.expression();
+                self.expect(Token::RightParen, "Expect ')' after condition.");
+                self.statement();
+                self.finish_node();
+            }
+            Token::For => self.for_statement(),
+            Token::LeftBrace => self.block("Expect '{' before block."),
+            _ => self.expression_statement(),
+        }
+    }
+
+    fn for_statement(&mut self) {
+        self.start_node(SyntaxKind::ForStmt);
+        self.bump();
+        self.expect(Token::LeftParen, "Expect '(' after 'for'.");
+
+        match self.peek() {
+            Token::Semicolon => self.bump(),
+            Token::Var => self.var_declaration(),
+            _ => self.expression_statement(),
+        }
+
+        if !self.at(&Token::Semicolon) {
+            self.expression();
+        }
+        self.expect(Token::Semicolon, "Expect ';' after loop condition.");
+
+        if !self.at(&Token::RightParen) {
+            self.expression();
+        }
+        self.expect(Token::RightParen, "Expect ')' after for clauses.");
+
+        self.statement();
+        self.finish_node();
+    }
+
+    fn block(&mut self, message: &str) {
+        self.start_node(SyntaxKind::Block);
+        self.expect(Token::LeftBrace, message);
+        while !self.at(&Token::RightBrace) && !self.at_end() {
+            let before = self.current.span.start;
+            self.declaration();
+            self.skip_if_stuck(before);
+        }
+        self.expect(Token::RightBrace, "Expect '}' after block.");
+        self.finish_node();
+    }
+
+    fn expression_statement(&mut self) {
+        self.start_node(SyntaxKind::ExprStmt);
+        self.expression();
+        self.expect(Token::Semicolon, "Expect ';' after expression.");
+        self.finish_node();
+    }
+
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::PrecAssignment);
+    }
+
+    /// Parse an expression whose operators bind at least as tightly as `precedence`, in the same
+    /// way as the compiler
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        let checkpoint = self.checkpoint();
+        self.prefix();
+
+        loop {
+            if self.at(&Token::Equal) {
+                if precedence > Precedence::PrecAssignment {
+                    break;
+                }
+                // the compiler can only assign to a variable or a field
+                if !self.is_assignment_target(checkpoint) {
+                    self.error("Invalid assignment target.");
+                }
+                // assignment is right associative
+                self.start_node_at(checkpoint, SyntaxKind::Assign);
+                self.bump();
+                self.parse_precedence(Precedence::PrecAssignment);
+                self.finish_node();
+                continue;
+            }
+
+            let operator_precedence = precedence_for_op(self.peek());
+            if operator_precedence == Precedence::PrecNone || operator_precedence < precedence {
+                break;
+            }
+
+            match self.peek() {
+                Token::LeftParen => {
+                    self.start_node_at(checkpoint, SyntaxKind::Call);
+                    self.arguments();
+                }
+                Token::Dot => {
+                    self.start_node_at(checkpoint, SyntaxKind::Get);
+                    self.bump();
+                    self.expect_identifier("Expect property name after '.'.");
+                }
+                _ => {
+                    self.start_node_at(checkpoint, SyntaxKind::Binary);
+                    self.bump();
+                    self.parse_precedence(operator_precedence.next());
+                }
+            }
+            self.finish_node();
+        }
+    }
+
+    // whether everything parsed since `checkpoint` is a single `Variable` or `Get` node
+    fn is_assignment_target(&self, checkpoint: usize) -> bool {
+        let Some((_, children)) = self.stack.last() else {
+            return false;
+        };
+        let mut parsed = children[checkpoint.min(children.len())..].iter().filter(
+            |child| !matches!(child, SyntaxElement::Token(token) if token.token.is_trivia()),
+        );
+        matches!(
+            (parsed.next(), parsed.next()),
+            (Some(SyntaxElement::Node(node)), None)
+                if matches!(node.kind, SyntaxKind::Variable | SyntaxKind::Get)
+        )
+    }
+
+    fn prefix(&mut self) {
+        match self.peek() {
+            Token::Number(_) | Token::String(_) | Token::True | Token::False | Token::Nil => {
+                self.start_node(SyntaxKind::Literal);
+                self.bump();
+            }
+            Token::Identifier(_) => {
+                self.start_node(SyntaxKind::Variable);
+                self.bump();
+            }
+            Token::This => {
+                self.start_node(SyntaxKind::This);
+                self.bump();
+            }
+            Token::Super => {
+                self.start_node(SyntaxKind::Super);
+                self.bump();
+                self.expect(Token::Dot, "Expect '.' after 'super'.");
+                self.expect_identifier("Expect superclass method name.");
+            }
+            Token::LeftParen => {
+                self.start_node(SyntaxKind::Grouping);
+                self.bump();
+                self.expression();
+                self.expect(Token::RightParen, "Expect ')' after expression.");
+            }
+            Token::Minus | Token::Bang => {
+                self.start_node(SyntaxKind::Unary);
+                self.bump();
+                self.parse_precedence(Precedence::PrecUnary);
+            }
+            Token::Interpolation(_) => self.interpolation(),
+            _ => {
+                self.error("Expect expression.");
+                return;
+            }
+        }
+        self.finish_node();
+    }
+
+    /// A string with interpolated expressions: its parts and the expressions between them
+    fn interpolation(&mut self) {
+        self.start_node(SyntaxKind::Interpolation);
+        loop {
+            // the part of the string before an expression
+            self.bump();
+            self.expression();
+            match self.peek() {
+                Token::Interpolation(_) => (),
+                Token::InterpolationEnd(_) => {
+                    self.bump();
+                    break;
+                }
+                _ => {
+                    self.error("Expect '}' after interpolated expression.");
+                    break;
+                }
+            }
+        }
+    }
+
+    fn arguments(&mut self) {
+        self.start_node(SyntaxKind::ArgList);
+        self.bump();
+        if !self.at(&Token::RightParen) {
+            loop {
+                self.expression();
+                if !self.at(&Token::Comma) {
+                    break;
+                }
+                self.bump();
+            }
+        }
+        self.expect(Token::RightParen, "Expect ')' after arguments.");
+        self.finish_node();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::memory::Heap;
+    use crate::Interpreter;
+    use std::fs;
+    use std::io;
+    use std::path::PathBuf;
+
+    fn corpus() -> Vec<(PathBuf, String)> {
+        let directory = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/corpus");
+        let mut files: Vec<(PathBuf, String)> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == "lox"))
+            .map(|path| {
+                let source = fs::read_to_string(&path).unwrap();
+                (path, source)
+            })
+            .collect();
+        files.sort();
+        assert!(!files.is_empty());
+        files
+    }
+
+    /// The tree as nested lists of node kinds and lexemes, leaving out trivia, like
+    /// `(Binary (Literal 1) + (Literal 2))`
+    fn outline(node: &SyntaxNode) -> String {
+        let children: Vec<String> = node
+            .children
+            .iter()
+            .filter_map(|child| match child {
+                SyntaxElement::Node(node) => Some(outline(node)),
+                SyntaxElement::Token(token_info) if token_info.token.is_trivia() => None,
+                SyntaxElement::Token(token_info) if token_info.token == Token::EOF => None,
+                SyntaxElement::Token(token_info) => Some(String::from(token_info.lexeme)),
+            })
+            .collect();
+        format!("({:?} {})", node.kind, children.join(" "))
+    }
+
+    fn assert_round_trips(source: &str) {
+        let tree = parse(source);
+        assert_eq!(tree.root.to_string(), source);
+
+        // and every token the scanner finds is in the tree, in order
+        let tokens: Vec<&str> = tree
+            .root
+            .tokens()
+            .iter()
+            .map(|token_info| token_info.lexeme)
+            .collect();
+        let scanned: Vec<&str> = Scanner::with_trivia(source)
+            .map(|token_info| token_info.lexeme)
+            .collect();
+        assert_eq!(tokens, scanned, "for {:?}", source);
+    }
+
+    #[test]
+    fn corpus_round_trips() {
+        for (path, source) in corpus() {
+            let tree = parse(&source);
+            assert_eq!(tree.root.to_string(), source, "for {:?}", path);
+            assert_round_trips(&source);
+            assert!(tree.errors.is_empty(), "{:?}: {:?}", path, tree.errors);
+
+            // the corpus is all working code, so the compiler should agree there are no errors
+            let mut interpreter = Interpreter::init();
+            interpreter.set_output(io::sink());
+            assert!(interpreter.eval(&source).is_ok(), "for {:?}", path);
+        }
+    }
+
+    // a small xorshift generator, so the mutations are random but the same on every run
+    struct Random(u64);
+
+    impl Random {
+        fn below(&mut self, limit: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % limit.max(1) as u64) as usize
+        }
+    }
+
+    #[test]
+    fn mangled_corpus_round_trips() {
+        // pieces of syntax that are most likely to confuse the scanner or the parser
+        let fragments = [
+            "\"", "${", "}", "{", "(", ")", ";", "/*", "*/", "//", "///", "\\", "@", "0x", "1e",
+            ".", "=", "class", "fun", "var", "super", "\n", "é", "\r\n",
+        ];
+        let mut random = Random(0x2545_f491_4f6c_dd1d);
+
+        for (_, source) in corpus() {
+            for _ in 0..200 {
+                let mut mangled: Vec<char> = source.chars().collect();
+                for _ in 0..=random.below(3) {
+                    let at = random.below(mangled.len() + 1);
+                    match random.below(3) {
+                        0 => {
+                            let end = (at + random.below(10)).min(mangled.len());
+                            mangled.drain(at..end);
+                        }
+                        1 => {
+                            let fragment = fragments[random.below(fragments.len())];
+                            mangled.splice(at..at, fragment.chars());
+                        }
+                        _ => mangled.truncate(at),
+                    }
+                }
+
+                assert_round_trips(&mangled.into_iter().collect::<String>());
+            }
+        }
+    }
+
+    #[test]
+    fn expressions_nest_by_precedence() {
+        let tree = parse("a.b = -1 + 2 * f(x, y).z;");
+        assert_eq!(
+            outline(&tree.root),
+            "(Script (ExprStmt (Assign (Get (Variable a) . b) = (Binary (Unary - (Literal 1)) + \
+             (Binary (Literal 2) * (Get (Call (Variable f) (ArgList ( (Variable x) , \
+             (Variable y) ))) . z)))) ;))"
+        );
+
+        let tree = parse("print \"a ${b} c ${d or e}\" + \"f\";");
+        assert_eq!(
+            outline(&tree.root),
+            "(Script (PrintStmt print (Binary (Interpolation \"a ${ (Variable b) } c ${ \
+             (Binary (Variable d) or (Variable e)) }\") + (Literal \"f\")) ;))"
+        );
+    }
+
+    #[test]
+    fn declarations_and_statements() {
+        let tree = parse(
+            "class A < B { m(x) { return super.m(this); } }\n\
+             fun f() { for (var i = 0; i < 1; i = i + 1) if (i) {} else while (nil) {} }",
+        );
+        assert_eq!(
+            outline(&tree.root),
+            "(Script (ClassDecl class A < B { (Method m (ParamList ( x )) (Block { (ReturnStmt \
+             return (Call (Super super . m) (ArgList ( (This this) ))) ;) })) }) (FunDecl fun f \
+             (ParamList ( )) (Block { (ForStmt for ( (VarDecl var i = (Literal 0) ;) (Binary \
+             (Variable i) < (Literal 1)) ; (Assign (Variable i) = (Binary (Variable i) + \
+             (Literal 1))) ) (IfStmt if ( (Variable i) ) (Block { }) else (WhileStmt while ( \
+             (Literal nil) ) (Block { })))) })))"
+        );
+    }
+
+    #[test]
+    fn trivia_belongs_to_the_node_it_comes_before() {
+        let source = "// about a\nvar a = 1; /* about b */ var b\n  = 2;\n// the end\n";
+        let tree = parse(source);
+        let declarations: Vec<String> = tree
+            .root
+            .child_nodes()
+            .map(|node| node.to_string())
+            .collect();
+
+        assert_eq!(
+            declarations,
+            vec!["// about a\nvar a = 1;", " /* about b */ var b\n  = 2;"]
+        );
+        assert!(tree.root.to_string().ends_with(";\n// the end\n"));
+    }
+
+    #[test]
+    fn doc_comments_attach_to_declarations() {
+        let tree = parse(
+            "/// Makes things.\n///\n///  Indented.\nclass A {\n  /// A method.\n  m() {}\n}\n\
+             // not docs\nfun f() {}",
+        );
+        let class = tree.root.child_nodes().next().unwrap();
+        let method = class.child_nodes().next().unwrap();
+        let function = tree.root.child_nodes().nth(1).unwrap();
+
+        assert_eq!(
+            class.doc_comment().as_deref(),
+            Some("Makes things.\n\n Indented.")
+        );
+        assert_eq!(method.doc_comment().as_deref(), Some("A method."));
+        assert_eq!(function.doc_comment(), None);
+    }
+
+    #[test]
+    fn errors_are_reported_and_skipped() {
+        let source =
+            "var = 1;\nprint (1 + ;\n}\nfun f( { }\nprint @ 2;\nprint 1 2 @ ;\nprint ;\nprint \"ok\";";
+        let tree = parse(source);
+
+        let messages: Vec<String> = tree.errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "[line 1] Error at '=': Expect variable name.",
+                "[line 2] Error at ';': Expect expression.",
+                "[line 3] Error at '}': Expect expression.",
+                "[line 4] Error at '{': Expect parameter name.",
+                "[line 5] Error: Unexpected character '@'.",
+                "[line 6] Error at '2': Expect ';' after value.",
+                "[line 7] Error at ';': Expect expression.",
+            ]
+        );
+        assert_eq!(tree.root.to_string(), source);
+
+        // the statement after the errors is still parsed properly
+        let last = tree.root.child_nodes().last().unwrap();
+        assert_eq!(outline(last), "(PrintStmt print (Literal \"ok\") ;)");
+    }
+
+    #[test]
+    fn invalid_assignment_targets_are_reported() {
+        let source = "a * b = c;\na + b = c;\na.b = c;\n(a) = c;\na = b = c;";
+        let tree = parse(source);
+
+        let messages: Vec<String> = tree.errors.iter().map(|error| error.to_string()).collect();
+        assert_eq!(
+            messages,
+            vec![
+                "[line 1] Error at '=': Invalid assignment target.",
+                "[line 2] Error at '=': Invalid assignment target.",
+                "[line 4] Error at '=': Invalid assignment target.",
+            ]
+        );
+        assert_eq!(tree.root.to_string(), source);
+
+        // the compiler rejects exactly the same assignments
+        let compiled = compile(source, &mut Heap::init()).unwrap_err();
+        assert_eq!(tree.errors, compiled);
+    }
+}
@@ -27,9 +27,19 @@ pub enum Token {
     Identifier(String),
     String(String),
     Number(f64),
+    // a string with interpolated expressions, like "a ${b} c ${d} e", is scanned as
+    // `Interpolation("a ")`, the tokens of `b`, `Interpolation(" c ")`, the tokens of `d` and
+    // finally `InterpolationEnd(" e")`
+    Interpolation(String),
+    InterpolationEnd(String),
 
-    // Source comment
+    // Source comment, either a line comment or a block comment, without its delimiters
     Comment(String),
+    // a `///` comment documenting the declaration that follows it, one token per line. The
+    // compiler skips these; `SyntaxNode::doc_comment` is what attaches them to declarations.
+    DocComment(String),
+    // a run of spaces, tabs and line breaks
+    Whitespace,
 
     // Keywords.
     And,
@@ -50,16 +60,80 @@ pub enum Token {
     While,
 
     // Utility
-    Error,
+    // something the scanner couldn't make sense of, with a message saying why
+    Error(String),
     EOF,
 }
 
+impl Token {
+    /// Whether this is a token that doesn't affect the meaning of the code around it, like a
+    /// comment
+    pub fn is_trivia(&self) -> bool {
+        matches!(
+            self,
+            Token::Comment(_) | Token::DocComment(_) | Token::Whitespace
+        )
+    }
+}
+
+/// Where a piece of code is in the source it was scanned from
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span {
+    // byte offsets of the first character and one past the last
+    pub start: usize,
+    pub end: usize,
+    // both 1-based, with the column counted in characters
+    pub line: i32,
+    pub column: usize,
+}
+
+impl Span {
+    /// The line of `source` this span starts on, without its line break. This is `None` if the
+    /// span doesn't point into `source`.
+    pub fn source_line<'s>(&self, source: &'s str) -> Option<&'s str> {
+        let (line_start, line_end) = self.line_bounds(source)?;
+        Some(source[line_start..line_end].trim_end_matches('\r'))
+    }
+
+    /// A row of carets lined up under the span in its `source_line` (or under as much of the span
+    /// as fits on that line)
+    pub fn carets(&self, source: &str) -> Option<String> {
+        let (line_start, line_end) = self.line_bounds(source)?;
+        let indent = source[line_start..self.start].chars().count();
+        let width = source
+            .get(self.start..self.end.min(line_end))
+            .map_or(0, |text| text.chars().count())
+            .max(1);
+        Some(format!("{}{}", " ".repeat(indent), "^".repeat(width)))
+    }
+
+    /// The line of `source` this span starts on, with a row of carets underneath the span
+    pub fn underline(&self, source: &str) -> Option<String> {
+        Some(format!(
+            "{}\n{}",
+            self.source_line(source)?,
+            self.carets(source)?
+        ))
+    }
+
+    // byte offsets of the start and end of the line the span starts on, or `None` if the span
+    // starts past the end of `source` (or in the middle of a character)
+    fn line_bounds(&self, source: &str) -> Option<(usize, usize)> {
+        let before = source.get(..self.start)?;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[self.start..]
+            .find('\n')
+            .map_or(source.len(), |i| self.start + i);
+        Some((line_start, line_end))
+    }
+}
+
 #[derive(Clone, Debug)]
-pub struct TokenInfo {
+pub struct TokenInfo<'a> {
     pub token: Token,
-    pub line: i32,
-    // should store the source string?
-    // source: String
+    pub span: Span,
+    // the exact source text the token was scanned from
+    pub lexeme: &'a str,
 }
 
 /// Get the keyword token corresponding to an identifier
@@ -86,3 +160,40 @@ pub fn check_keyword(identifier: &str) -> Option<Token> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn underline_a_span() {
+        let source = "var a = 1;\nprint a +\n  b;";
+        let span = |start, end| Span {
+            start,
+            end,
+            ..Span::default()
+        };
+
+        let underline = |start, end| span(start, end).underline(source).unwrap();
+
+        assert_eq!(underline(4, 5), "var a = 1;\n    ^");
+        assert_eq!(underline(11, 16), "print a +\n^^^^^");
+        // spans running over several lines are cut off at the end of the first
+        assert_eq!(underline(19, 24), "print a +\n        ^");
+        // the end of the file still gets a caret
+        assert_eq!(underline(25, 25), "  b;\n    ^");
+    }
+
+    #[test]
+    fn spans_outside_the_source_have_no_underline() {
+        let span = Span {
+            start: 12,
+            end: 15,
+            ..Span::default()
+        };
+
+        assert_eq!(span.underline("print 1;"), None);
+        // a span can't start in the middle of a character either
+        assert_eq!(span.underline("print \"éééééé\";"), None);
+    }
+}
@@ -1 +1,152 @@
-pub type Value = f64;
+use crate::object::ObjRef;
+use std::fmt;
+use std::rc::{Rc, Weak};
+
+/// A Lox value as seen by the virtual machine.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(ObjRef),
+}
+
+impl Value {
+    /// `nil` and `false` are falsey, every other value is truthy.
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    pub fn as_string(&self) -> Option<&str> {
+        match self {
+            Value::Obj(obj) => obj.as_string().map(|string| string.chars.as_str()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => f.pad("nil"),
+            Value::Bool(b) => f.pad(&b.to_string()),
+            Value::Number(num) => f.pad(&num.to_string()),
+            Value::Obj(obj) => obj.fmt(f),
+        }
+    }
+}
+
+/// A Lox value as seen by the program hosting the interpreter. Unlike a `Value`, this doesn't
+/// point into the heap, apart from through an `ObjectHandle`, so the host can hold onto it for as
+/// long as it likes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoxValue {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    // any other kind of object, like a function or an instance
+    Object(ObjectHandle),
+}
+
+impl fmt::Display for LoxValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoxValue::Nil => f.pad("nil"),
+            LoxValue::Bool(b) => f.pad(&b.to_string()),
+            LoxValue::Number(num) => f.pad(&num.to_string()),
+            LoxValue::String(string) => f.pad(string),
+            LoxValue::Object(handle) => handle.fmt(f),
+        }
+    }
+}
+
+/// An opaque reference to an object owned by an interpreter. The object is kept alive for as long
+/// as any clone of the handle is, and the handle can only be passed back to the interpreter it
+/// came from.
+#[derive(Clone)]
+pub struct ObjectHandle {
+    pin: Rc<Pin>,
+}
+
+/// What the VM holds onto for each object handed out to the host. It only keeps a weak reference,
+/// so that the object can be collected again once the last handle is dropped.
+pub(crate) struct Pin {
+    // the id of the VM whose heap the object is in
+    pub(crate) vm: usize,
+    pub(crate) object: ObjRef,
+    // how the object prints, worked out up front since the object can't be looked at once its VM
+    // is gone
+    description: String,
+}
+
+impl ObjectHandle {
+    pub(crate) fn new(vm: usize, object: ObjRef) -> ObjectHandle {
+        ObjectHandle {
+            pin: Rc::new(Pin {
+                vm,
+                object,
+                description: object.to_string(),
+            }),
+        }
+    }
+
+    pub(crate) fn pin(&self) -> Weak<Pin> {
+        Rc::downgrade(&self.pin)
+    }
+
+    /// The object behind the handle, if it belongs to the VM with the given id
+    pub(crate) fn object_in(&self, vm: usize) -> Option<ObjRef> {
+        (self.pin.vm == vm).then_some(self.pin.object)
+    }
+}
+
+impl PartialEq for ObjectHandle {
+    fn eq(&self, other: &ObjectHandle) -> bool {
+        self.pin.vm == other.pin.vm && self.pin.object == other.pin.object
+    }
+}
+
+impl fmt::Display for ObjectHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(&self.pin.description)
+    }
+}
+
+impl fmt::Debug for ObjectHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ObjectHandle")
+            .field(&self.pin.description)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truthiness() {
+        assert!(Value::Nil.is_falsey());
+        assert!(Value::Bool(false).is_falsey());
+        assert!(!Value::Bool(true).is_falsey());
+        assert!(!Value::Number(0.0).is_falsey());
+    }
+
+    #[test]
+    fn equality() {
+        assert!(Value::Nil == Value::Nil);
+        assert!(Value::Bool(true) == Value::Bool(true));
+        assert!(Value::Number(1.0) == Value::Number(1.0));
+        assert!(Value::Number(0.0) != Value::Bool(false));
+        assert!(Value::Nil != Value::Bool(false));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Value::Nil.to_string(), "nil");
+        assert_eq!(Value::Bool(true).to_string(), "true");
+        assert_eq!(Value::Number(3.0).to_string(), "3");
+        assert_eq!(Value::Number(2.5).to_string(), "2.5");
+    }
+}
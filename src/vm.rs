@@ -1,100 +1,1626 @@
 use crate::chunk::{Chunk, OpCode};
-use crate::compiler::compile;
-use crate::value::Value;
+use crate::compiler::{compile_with_roots, CompileError};
+use crate::memory::{Heap, Roots};
+use crate::natives;
+use crate::object::{
+    NativeFn, ObjBoundMethod, ObjClass, ObjClosure, ObjFunction, ObjInstance, ObjKind, ObjNative,
+    ObjRef, ObjUpvalue, UpvalueLocation,
+};
+use crate::token::Span;
+use crate::value::{LoxValue, ObjectHandle, Pin, Value};
 use log::{log_enabled, Level};
-use std::cell::{Cell, RefCell};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The default limit on how deeply calls can be nested before we report a stack overflow
+pub const FRAMES_MAX: usize = 64;
+
+/// What a native function can do with the VM calling it. This gives access to the globals, but not
+/// to running more code, which would pull the rug out from under the code that made the call.
+pub struct NativeContext<'v> {
+    vm: &'v mut VM,
+}
+
+impl NativeContext<'_> {
+    pub(crate) fn init(vm: &mut VM) -> NativeContext<'_> {
+        NativeContext { vm }
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<LoxValue> {
+        self.vm
+            .get_global(name)
+            .map(|value| self.vm.export_value(value))
+    }
+
+    /// Define a global variable, or overwrite it if it already exists. This fails if `value` holds
+    /// an `ObjectHandle` from a different interpreter.
+    pub fn set_global(&mut self, name: &str, value: &LoxValue) -> Result<(), RuntimeError> {
+        self.vm.set_host_global(name, value)
+    }
+}
+
+/// The id the next VM gets, which tells apart the heaps that `ObjectHandle`s point into
+static NEXT_VM_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A single ongoing function call
+struct CallFrame {
+    closure: ObjRef,
+    // ip stands for 'instruction pointer' and stores the index of the next `OpCode` to run in the
+    // function's chunk
+    ip: usize,
+    // the index of the first stack slot belonging to this call, which holds the function itself.
+    // Its arguments and locals come right after.
+    slot_base: usize,
+}
 
 pub struct VM {
-    // ip stands for 'instruction pointer' and stores a pointer to the current `OpCode`. We want to
-    // wrap it in a `Cell` because we need to be able to mutate it, but we don't want to deal with
-    // `&mut self` references.
-    ip: Cell<Option<usize>>,
-    stack: RefCell<Vec<Value>>,
+    frames: Vec<CallFrame>,
+    stack: Vec<Value>,
+    // all of the objects (and interned strings) created by this VM. This outlives any single call
+    // to `interpret`, so a string seen once is never allocated again.
+    heap: Heap,
+    // global variables, keyed by their (interned) name. These also stick around between calls to
+    // `interpret`, which is what lets the REPL remember definitions from one line to the next.
+    globals: HashMap<ObjRef, Value>,
+    // upvalues which still point at a variable on the stack, ordered by stack slot. When a
+    // variable goes out of scope, its upvalue gets closed and removed from this list.
+    open_upvalues: Vec<ObjRef>,
+    max_frames: usize,
+    // where `print` statements write to
+    output: Box<dyn Write>,
+    id: usize,
+    // the objects handed out to the host as `ObjectHandle`s, which stay alive until every clone
+    // of their handle has been dropped
+    pinned: RefCell<Vec<Weak<Pin>>>,
+    // the interned name of initializers, kept around so that it doesn't have to be looked up
+    // every time a class is called
+    init_string: ObjRef,
+}
+
+/// Everything the VM holds onto outside of the heap. This borrows the VM's fields separately from
+/// the heap itself, so that the roots can be marked while the heap is being mutated.
+struct VmRoots<'v> {
+    stack: &'v [Value],
+    frames: &'v [CallFrame],
+    globals: &'v HashMap<ObjRef, Value>,
+    open_upvalues: &'v [ObjRef],
+    pinned: &'v [Weak<Pin>],
+    init_string: ObjRef,
+}
+
+impl Roots for VmRoots<'_> {
+    fn mark_roots(&self, heap: &mut Heap) {
+        for value in self.stack {
+            heap.mark_value(*value);
+        }
+        for frame in self.frames {
+            heap.mark_object(frame.closure);
+        }
+        heap.mark_object(self.init_string);
+        for (name, value) in self.globals {
+            heap.mark_object(*name);
+            heap.mark_value(*value);
+        }
+        for upvalue in self.open_upvalues {
+            heap.mark_object(*upvalue);
+        }
+        for pin in self.pinned.iter().filter_map(Weak::upgrade) {
+            heap.mark_object(pin.object);
+        }
+    }
+}
+
+/// Why a piece of Lox code couldn't be run to completion
+#[derive(Debug)]
+pub enum LoxError {
+    // every error found in the source, in the order they appear
+    Compile(Vec<CompileError>),
+    Runtime(RuntimeError),
+}
+
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoxError::Compile(errors) => {
+                let errors: Vec<String> = errors.iter().map(|error| error.to_string()).collect();
+                f.write_str(&errors.join("\n"))
+            }
+            LoxError::Runtime(error) => error.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for LoxError {}
+
+impl From<Vec<CompileError>> for LoxError {
+    fn from(errors: Vec<CompileError>) -> LoxError {
+        LoxError::Compile(errors)
+    }
+}
+
+impl From<RuntimeError> for LoxError {
+    fn from(error: RuntimeError) -> LoxError {
+        LoxError::Runtime(error)
+    }
+}
+
+/// An error raised while the VM is running, along with the source line of the instruction that
+/// caused it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    // where the failing instruction came from in the source. This is all zeroes if the VM wasn't
+    // running any code at the time.
+    pub span: Span,
+    // the source code `span` points into. When a function defined by an earlier call to
+    // `interpret` fails, this is the source from that call rather than the latest one.
+    pub source: Rc<str>,
+    // one entry per call that was in progress, innermost first
+    pub trace: Vec<TraceFrame>,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in &self.trace {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// A call that was in progress when a runtime error happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    // the name of the function being run, which is `None` for the top level script
+    pub function: Option<String>,
+    // the instruction the call was at: the failing one for the innermost call, otherwise the
+    // call to the next function in
+    pub span: Span,
 }
 
-pub enum InterpretResult {
-    Ok,
-    CompileError,
-    RuntimeError,
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}] in {}()", self.span.line, name),
+            None => write!(f, "[line {}] in script", self.span.line),
+        }
+    }
 }
 
+// `$value_type` is the `Value` variant the result gets wrapped in, so that the same macro works
+// for both arithmetic and comparison operators
 macro_rules! binary_op{
-    ($self:ident, $op:tt)=> {
+    ($self:ident, $value_type:path, $op:tt)=> {
         {
-            let mut stack = $self.stack.borrow_mut();
-            let b = stack.pop().unwrap();
-            let a = stack.pop().unwrap();
-            stack.push(a $op b);
+            let b = $self.pop()?;
+            let a = $self.pop()?;
+            match (a, b) {
+                (Value::Number(a), Value::Number(b)) => $self.push($value_type(a $op b)),
+                _ => return Err($self.runtime_error("Operands must be numbers.")),
+            }
         }
     }
 }
 
 impl VM {
     pub fn init() -> VM {
-        VM {
-            ip: Cell::new(None),
-            stack: RefCell::new(vec![]),
+        // the heap never collects on its own, so this is safe before there are any roots to mark
+        let mut heap = Heap::init();
+        let init_string = heap.copy_string("init");
+
+        let mut vm = VM {
+            frames: vec![],
+            stack: vec![],
+            heap,
+            globals: HashMap::new(),
+            open_upvalues: vec![],
+            max_frames: FRAMES_MAX,
+            output: Box::new(io::stdout()),
+            id: NEXT_VM_ID.fetch_add(1, Ordering::Relaxed),
+            pinned: RefCell::new(vec![]),
+            init_string,
+        };
+
+        vm.define_native("clock", 0, Box::new(natives::clock));
+        vm
+    }
+
+    /// Make a Rust function callable from Lox as a global called `name`. Calls with anything other
+    /// than `arity` arguments are rejected before `function` is ever run.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        // both objects are kept on the stack until they're safely in `globals`, in case
+        // allocating the second one triggers a collection
+        let name = self.collecting_heap().copy_string(name);
+        self.push(Value::Obj(name));
+        let native = self.collecting_heap().allocate(ObjKind::Native(ObjNative {
+            name,
+            arity,
+            function,
+        }));
+        self.push(Value::Obj(native));
+
+        self.globals.insert(name, Value::Obj(native));
+        self.stack.truncate(self.stack.len() - 2);
+    }
+
+    /// Build a `RuntimeError` pointing at the instruction that was just executed, with a trace of
+    /// every call in progress
+    pub fn runtime_error(&self, message: &str) -> RuntimeError {
+        let spans: Vec<(Span, &ObjFunction)> = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = function_of(&frame.closure);
+                // `ip` has already moved past the failing instruction
+                let index = frame.ip.saturating_sub(1);
+                (function.chunk.get_span(index).unwrap_or_default(), function)
+            })
+            .collect();
+
+        RuntimeError {
+            message: String::from(message),
+            span: spans.first().map_or(Span::default(), |(span, _)| *span),
+            source: spans.first().map_or(Rc::from(""), |(_, function)| {
+                Rc::clone(&function.chunk.source)
+            }),
+            trace: spans
+                .iter()
+                .map(|(span, function)| TraceFrame {
+                    function: function.name.map(|name| name.to_string()),
+                    span: *span,
+                })
+                .collect(),
+        }
+    }
+
+    /// Change how deeply calls can be nested before the VM reports a stack overflow
+    pub fn set_max_frames(&mut self, max_frames: usize) {
+        self.max_frames = max_frames;
+    }
+
+    /// Send the output of `print` statements somewhere other than stdout
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    /// Look up a global variable. If the value is an object, it's only guaranteed to stay alive
+    /// for as long as something in the VM still refers to it.
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        // if the name was never interned, there can't be a global with that name
+        let name = self.heap.find_string(name)?;
+        self.globals.get(&name).copied()
+    }
+
+    /// Define a global variable, or overwrite it if it already exists
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        // keep the value alive while the name is interned
+        self.push(value);
+        let name = self.collecting_heap().copy_string(name);
+        self.globals.insert(name, value);
+        self.stack.pop();
+    }
+
+    /// Like `set_global`, for a value from the host. This fails if `value` is a handle to an
+    /// object from another VM.
+    pub fn set_host_global(&mut self, name: &str, value: &LoxValue) -> Result<(), RuntimeError> {
+        let value = self.import_value(value).ok_or_else(|| {
+            self.runtime_error(
+                "Object handles can only be used with the interpreter they came from.",
+            )
+        })?;
+        self.set_global(name, value);
+        Ok(())
+    }
+
+    /// Copy `value` out for the host. Strings are copied, and any other object is kept alive for
+    /// as long as the host holds onto its handle.
+    pub fn export_value(&self, value: Value) -> LoxValue {
+        match value {
+            Value::Nil => LoxValue::Nil,
+            Value::Bool(b) => LoxValue::Bool(b),
+            Value::Number(num) => LoxValue::Number(num),
+            Value::Obj(obj) => match obj.as_string() {
+                Some(string) => LoxValue::String(string.chars.clone()),
+                None => {
+                    let handle = ObjectHandle::new(self.id, obj);
+                    let mut pinned = self.pinned.borrow_mut();
+                    // forget about the objects whose handles are all gone
+                    pinned.retain(|pin| pin.strong_count() > 0);
+                    pinned.push(handle.pin());
+                    LoxValue::Object(handle)
+                }
+            },
         }
     }
 
-    pub fn interpret(&self, source: &str) -> InterpretResult {
-        let chunk = compile(source).unwrap();
-        self.ip.set(Some(0));
-        self.run(&chunk)
+    /// Turn a value from the host back into one the VM can use, interning any string. This is
+    /// `None` if `value` is a handle to an object from another VM.
+    ///
+    /// A new string isn't rooted anywhere, so it has to be made reachable before the next
+    /// allocation.
+    pub fn import_value(&mut self, value: &LoxValue) -> Option<Value> {
+        match value {
+            LoxValue::Nil => Some(Value::Nil),
+            LoxValue::Bool(b) => Some(Value::Bool(*b)),
+            LoxValue::Number(num) => Some(Value::Number(*num)),
+            LoxValue::String(string) => {
+                Some(Value::Obj(self.collecting_heap().copy_string(string)))
+            }
+            LoxValue::Object(handle) => handle.object_in(self.id).map(Value::Obj),
+        }
+    }
+
+    /// Compile and run `source`, returning the value of the expression statement it ends with (if
+    /// there is one) or `nil`
+    pub fn interpret(&mut self, source: &str) -> Result<Value, LoxError> {
+        let pinned = self.pinned.borrow();
+        let roots = VmRoots {
+            stack: &self.stack,
+            frames: &self.frames,
+            globals: &self.globals,
+            open_upvalues: &self.open_upvalues,
+            pinned: &pinned,
+            init_string: self.init_string,
+        };
+        let function = compile_with_roots(source, &mut self.heap, &roots);
+        drop(pinned);
+        let function = function?;
+
+        // The script is wrapped in a closure like any other function, although it can never
+        // capture anything. The function stays on the stack while the closure is allocated, so
+        // that the garbage collector can't free it in between.
+        self.push(Value::Obj(function));
+        let script = self
+            .collecting_heap()
+            .allocate(ObjKind::Closure(ObjClosure {
+                function,
+                upvalues: vec![],
+            }));
+        self.stack.pop();
+        self.push(Value::Obj(script));
+        let result = self
+            .call_value(Value::Obj(script), 0)
+            .and_then(|_| self.run());
+
+        result.map_err(|error| {
+            // whatever was left on the stack belonged to the program that just failed, so we
+            // throw it away to leave the VM ready for the next one
+            self.reset_stack();
+            LoxError::Runtime(error)
+        })
     }
 
     // private, VM-use only functions
-    fn run(&self, chunk: &Chunk) -> InterpretResult {
-        while let Some((opcode, index)) = self.read_byte(chunk) {
+    fn run(&mut self) -> Result<Value, RuntimeError> {
+        while let Some((opcode, index, closure)) = self.read_byte() {
+            let chunk = &function_of(&closure).chunk;
+
             if log_enabled!(Level::Debug) {
                 self.print_stack();
-                chunk.disassemble_instruction(index, opcode);
+                chunk.disassemble_instruction(index, &opcode);
             }
 
             match opcode {
+                OpCode::OpPrint => {
+                    let value = self.pop()?;
+                    if writeln!(self.output, "{}", value).is_err() {
+                        return Err(self.runtime_error("Unable to write output."));
+                    }
+                }
+                OpCode::OpJump(offset) => self.frame_mut().ip += offset as usize,
+                OpCode::OpJumpIfFalse(offset) => {
+                    if self.peek(0)?.is_falsey() {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                OpCode::OpLoop(offset) => self.frame_mut().ip -= offset as usize,
+                OpCode::OpCall(arg_count) => {
+                    let callee = self.peek(arg_count)?;
+                    self.call_value(callee, arg_count)?;
+                }
+                OpCode::OpClosure(index) => {
+                    let function = match chunk.get_constant(index) {
+                        Value::Obj(function) if function.as_function().is_some() => function,
+                        _ => return Err(self.runtime_error("Expected a function.")),
+                    };
+
+                    let slot_base = self.frame().slot_base;
+                    let enclosing = closure_of(&closure);
+                    let upvalues = function_of_obj(&function)
+                        .upvalues
+                        .iter()
+                        .map(|upvalue| {
+                            if upvalue.is_local {
+                                self.capture_upvalue(slot_base + upvalue.index)
+                            } else {
+                                enclosing.upvalues[upvalue.index]
+                            }
+                        })
+                        .collect();
+
+                    let closure = self
+                        .collecting_heap()
+                        .allocate(ObjKind::Closure(ObjClosure { function, upvalues }));
+                    self.push(Value::Obj(closure));
+                }
+                OpCode::OpGetUpvalue(index) => {
+                    let upvalue = closure_of(&closure).upvalues[index];
+                    let value = match *upvalue_of(&upvalue).location.borrow() {
+                        UpvalueLocation::Open(slot) => self.stack.get(slot).copied(),
+                        UpvalueLocation::Closed(value) => Some(value),
+                    };
+                    match value {
+                        Some(value) => self.push(value),
+                        None => return Err(self.runtime_error("Stack underflow.")),
+                    }
+                }
+                OpCode::OpSetUpvalue(index) => {
+                    let value = self.peek(0)?;
+                    let upvalue = closure_of(&closure).upvalues[index];
+                    let mut location = upvalue_of(&upvalue).location.borrow_mut();
+                    match &mut *location {
+                        UpvalueLocation::Open(slot) => {
+                            if let Some(variable) = self.stack.get_mut(*slot) {
+                                *variable = value;
+                            }
+                        }
+                        UpvalueLocation::Closed(variable) => *variable = value,
+                    }
+                }
+                OpCode::OpCloseUpvalue => {
+                    self.close_upvalues(self.stack.len().saturating_sub(1));
+                    self.pop()?;
+                }
+                OpCode::OpClass(index) => {
+                    let name = self.read_string(chunk, index)?;
+                    let class = self
+                        .collecting_heap()
+                        .allocate(ObjKind::Class(ObjClass::init(name)));
+                    self.push(Value::Obj(class));
+                }
+                OpCode::OpMethod(index) => {
+                    let name = self.read_string(chunk, index)?;
+                    let method = self.peek(0)?;
+                    if let Value::Obj(class) = self.peek(1)? {
+                        if let Some(class) = class.as_class() {
+                            class.methods.borrow_mut().insert(name, method);
+                        }
+                    }
+                    self.pop()?;
+                }
+                OpCode::OpGetProperty(index) => {
+                    let instance = match self.peek(0)? {
+                        Value::Obj(obj) if obj.as_instance().is_some() => obj,
+                        _ => return Err(self.runtime_error("Only instances have properties.")),
+                    };
+                    let name = self.read_string(chunk, index)?;
+
+                    // fields shadow methods of the same name
+                    let field = instance_of(&instance).fields.borrow().get(&name).copied();
+                    match field {
+                        Some(value) => {
+                            self.pop()?;
+                            self.push(value);
+                        }
+                        None => self.bind_method(instance_of(&instance).class, name)?,
+                    }
+                }
+                OpCode::OpSetProperty(index) => {
+                    let instance = match self.peek(1)? {
+                        Value::Obj(obj) if obj.as_instance().is_some() => obj,
+                        _ => return Err(self.runtime_error("Only instances have fields.")),
+                    };
+                    let name = self.read_string(chunk, index)?;
+                    let value = self.pop()?;
+                    instance_of(&instance)
+                        .fields
+                        .borrow_mut()
+                        .insert(name, value);
+
+                    // like any assignment, this leaves the assigned value on the stack
+                    self.pop()?;
+                    self.push(value);
+                }
+                OpCode::OpInvoke(index, arg_count) => {
+                    let name = self.read_string(chunk, index)?;
+                    self.invoke(name, arg_count)?;
+                }
+                OpCode::OpInherit => {
+                    let superclass = match self.peek(1)? {
+                        Value::Obj(obj) if obj.as_class().is_some() => obj,
+                        _ => return Err(self.runtime_error("Superclass must be a class.")),
+                    };
+                    if let Value::Obj(subclass) = self.peek(0)? {
+                        // Copying the methods down now means method calls never have to walk up
+                        // the class hierarchy. This happens before the subclass's own methods
+                        // are added, so those override the inherited ones.
+                        let methods = class_of(&superclass).methods.borrow().clone();
+                        class_of(&subclass).methods.borrow_mut().extend(methods);
+                    }
+                    self.pop()?;
+                }
+                OpCode::OpGetSuper(index) => {
+                    let name = self.read_string(chunk, index)?;
+                    let superclass = self.pop_class()?;
+                    self.bind_method(superclass, name)?;
+                }
+                OpCode::OpSuperInvoke(index, arg_count) => {
+                    let name = self.read_string(chunk, index)?;
+                    let superclass = self.pop_class()?;
+                    self.invoke_from_class(superclass, name, arg_count)?;
+                }
                 OpCode::OpReturn => {
-                    let mut stack = self.stack.borrow_mut();
-                    let value = stack.pop();
+                    let result = self.pop()?;
+                    let frame = self.frames.pop();
 
-                    if let Some(val) = value {
-                        println!("{}", val);
+                    if let Some(frame) = &frame {
+                        // any of the function's locals which were captured have to outlive it
+                        self.close_upvalues(frame.slot_base);
                     }
+
+                    if self.frames.is_empty() {
+                        // we've returned from the top-level script, so pop it and we're done
+                        self.pop()?;
+                        return Ok(result);
+                    }
+
+                    // throw away the callee, its arguments and its locals
+                    if let Some(frame) = frame {
+                        self.stack.truncate(frame.slot_base);
+                    }
+                    self.push(result);
+                }
+                OpCode::OpNil => self.push(Value::Nil),
+                OpCode::OpTrue => self.push(Value::Bool(true)),
+                OpCode::OpFalse => self.push(Value::Bool(false)),
+                OpCode::OpPop => {
+                    self.pop()?;
                 }
-                OpCode::OpAdd => binary_op!(self, +),
-                OpCode::OpSubtract => binary_op!(self, -),
-                OpCode::OpMultiply => binary_op!(self, *),
-                OpCode::OpDivide => binary_op!(self, /),
-                OpCode::OpNegate => {
-                    let mut stack = self.stack.borrow_mut();
-                    if let Some(num) = stack.pop() {
-                        stack.push(-num);
-                    } else {
-                        // TODO do something more sensible here
-                        panic!(
-                            "{}",
-                            format!("I tried to negate something that wasn't there :/")
-                        )
+                OpCode::OpGetLocal(slot) => {
+                    let value = self.stack.get(self.frame().slot_base + slot).copied();
+                    match value {
+                        Some(value) => self.push(value),
+                        None => return Err(self.runtime_error("Stack underflow.")),
+                    }
+                }
+                OpCode::OpSetLocal(slot) => {
+                    let value = self.peek(0)?;
+                    let slot = self.frame().slot_base + slot;
+                    if let Some(local) = self.stack.get_mut(slot) {
+                        *local = value;
+                    }
+                }
+                OpCode::OpDefineGlobal(index) => {
+                    let name = self.read_string(chunk, index)?;
+                    let value = self.pop()?;
+                    self.globals.insert(name, value);
+                }
+                OpCode::OpGetGlobal(index) => {
+                    let name = self.read_string(chunk, index)?;
+                    match self.globals.get(&name).copied() {
+                        Some(value) => self.push(value),
+                        None => return Err(self.undefined_variable(name)),
+                    }
+                }
+                OpCode::OpSetGlobal(index) => {
+                    let name = self.read_string(chunk, index)?;
+                    // assignment is an expression, so the value stays on the stack
+                    let value = self.peek(0)?;
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = value,
+                        None => return Err(self.undefined_variable(name)),
+                    }
+                }
+                OpCode::OpEqual => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    self.push(Value::Bool(a == b));
+                }
+                OpCode::OpGreater => binary_op!(self, Value::Bool, >),
+                OpCode::OpLess => binary_op!(self, Value::Bool, <),
+                OpCode::OpAdd => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    match (a, b) {
+                        (Value::Number(a), Value::Number(b)) => self.push(Value::Number(a + b)),
+                        _ => match (a.as_string(), b.as_string()) {
+                            (Some(a), Some(b)) => {
+                                // the operands have been popped, but we're done with them by the
+                                // time the new string could trigger a collection
+                                let chars = a.to_owned() + b;
+                                let string = self.collecting_heap().take_string(chars);
+                                self.push(Value::Obj(string));
+                            }
+                            _ => {
+                                return Err(self
+                                    .runtime_error("Operands must be two numbers or two strings."))
+                            }
+                        },
+                    }
+                }
+                OpCode::OpSubtract => binary_op!(self, Value::Number, -),
+                OpCode::OpMultiply => binary_op!(self, Value::Number, *),
+                OpCode::OpDivide => binary_op!(self, Value::Number, /),
+                OpCode::OpNot => {
+                    let value = self.pop()?;
+                    self.push(Value::Bool(value.is_falsey()));
+                }
+                OpCode::OpNegate => match self.pop()? {
+                    Value::Number(num) => self.push(Value::Number(-num)),
+                    _ => return Err(self.runtime_error("Operand must be a number.")),
+                },
+                OpCode::OpToString => {
+                    if self.peek(0)?.as_string().is_none() {
+                        let chars = self.pop()?.to_string();
+                        let string = self.collecting_heap().take_string(chars);
+                        self.push(Value::Obj(string));
                     }
                 }
                 OpCode::OpConstant(index) => {
-                    let index = *index;
-                    let value = chunk.constants[index];
-                    let mut stack = self.stack.borrow_mut();
-                    stack.push(value);
+                    let value = chunk.get_constant(index);
+                    self.push(value);
                 }
             };
         }
-        InterpretResult::Ok
+        Ok(Value::Nil)
     }
 
-    fn read_byte<'a>(&'a self, chunk: &'a Chunk) -> Option<(&OpCode, usize)> {
-        self.ip.take().and_then(|index| {
-            self.ip.set(Some(index + 1));
-            chunk.code.get(index).map(|opcode| (opcode, index))
-        })
+    /// Fetch the next instruction of the current call, along with its index in the chunk and the
+    /// closure it belongs to
+    fn read_byte(&mut self) -> Option<(OpCode, usize, ObjRef)> {
+        let frame = self.frames.last_mut()?;
+        let index = frame.ip;
+        let opcode = *function_of(&frame.closure).chunk.code.get(index)?;
+        frame.ip += 1;
+
+        Some((opcode, index, frame.closure))
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Result<(), RuntimeError> {
+        let callee = match callee {
+            Value::Obj(obj) => obj,
+            _ => return Err(self.runtime_error("Can only call functions and classes.")),
+        };
+
+        match &callee.kind {
+            ObjKind::Closure(_) => self.call(callee, arg_count),
+            ObjKind::Native(native) => {
+                if arg_count != native.arity {
+                    return Err(self.runtime_error(&format!(
+                        "Expected {} arguments but got {}.",
+                        native.arity, arg_count
+                    )));
+                }
+
+                // the arguments stay on the stack during the call, so they can't be collected
+                let args_start = self.stack.len() - arg_count;
+                let args: Vec<LoxValue> = self.stack[args_start..]
+                    .iter()
+                    .map(|arg| self.export_value(*arg))
+                    .collect();
+                let result = (native.function)(&mut NativeContext::init(self), &args)
+                    .map_err(|message| self.runtime_error(&message))?;
+                let result = self.import_value(&result).ok_or_else(|| {
+                    self.runtime_error("Native function returned an object from another VM.")
+                })?;
+
+                // throw away the native itself along with its arguments
+                self.stack.truncate(args_start - 1);
+                self.push(result);
+                Ok(())
+            }
+            ObjKind::BoundMethod(bound) => {
+                // the receiver takes the callee's place in slot 0, where the method expects
+                // to find `this`
+                self.set_callee(arg_count, bound.receiver);
+                self.call(bound.method, arg_count)
+            }
+            ObjKind::Class(class) => {
+                let instance = self
+                    .collecting_heap()
+                    .allocate(ObjKind::Instance(ObjInstance::init(callee)));
+                self.set_callee(arg_count, Value::Obj(instance));
+
+                let initializer = class.methods.borrow().get(&self.init_string).copied();
+                match initializer {
+                    Some(Value::Obj(initializer)) => self.call(initializer, arg_count),
+                    _ if arg_count != 0 => {
+                        Err(self
+                            .runtime_error(&format!("Expected 0 arguments but got {}.", arg_count)))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            _ => Err(self.runtime_error("Can only call functions and classes.")),
+        }
+    }
+
+    /// Replace the value being called, which sits just below its arguments on the stack
+    fn set_callee(&mut self, arg_count: usize, value: Value) {
+        let slot = self.stack.len() - arg_count - 1;
+        self.stack[slot] = value;
+    }
+
+    /// Call the method `name` on the receiver sitting below the arguments on the stack
+    fn invoke(&mut self, name: ObjRef, arg_count: usize) -> Result<(), RuntimeError> {
+        let instance = match self.peek(arg_count)? {
+            Value::Obj(obj) if obj.as_instance().is_some() => obj,
+            _ => return Err(self.runtime_error("Only instances have methods.")),
+        };
+
+        // a field holding a function looks exactly like a method call, but isn't one
+        let field = instance_of(&instance).fields.borrow().get(&name).copied();
+        if let Some(field) = field {
+            self.set_callee(arg_count, field);
+            return self.call_value(field, arg_count);
+        }
+
+        self.invoke_from_class(instance_of(&instance).class, name, arg_count)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: ObjRef,
+        name: ObjRef,
+        arg_count: usize,
+    ) -> Result<(), RuntimeError> {
+        match class_of(&class).methods.borrow().get(&name).copied() {
+            Some(Value::Obj(method)) => self.call(method, arg_count),
+            _ => Err(self.undefined_property(name)),
+        }
+    }
+
+    /// Replace the instance on top of the stack with its method `name`, bound to the instance
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Result<(), RuntimeError> {
+        let method = match class_of(&class).methods.borrow().get(&name).copied() {
+            Some(Value::Obj(method)) => method,
+            _ => return Err(self.undefined_property(name)),
+        };
+
+        // the receiver stays on the stack until the bound method is allocated
+        let receiver = self.peek(0)?;
+        let bound = self
+            .collecting_heap()
+            .allocate(ObjKind::BoundMethod(ObjBoundMethod { receiver, method }));
+        self.pop()?;
+        self.push(Value::Obj(bound));
+        Ok(())
+    }
+
+    /// Start a call to `closure`, whose arguments are already on top of the stack
+    fn call(&mut self, closure: ObjRef, arg_count: usize) -> Result<(), RuntimeError> {
+        let arity = function_of(&closure).arity;
+        if arg_count != arity {
+            return Err(self.runtime_error(&format!(
+                "Expected {} arguments but got {}.",
+                arity, arg_count
+            )));
+        }
+
+        if self.frames.len() >= self.max_frames {
+            return Err(self.runtime_error("Stack overflow."));
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slot_base: self.stack.len() - arg_count - 1,
+        });
+        Ok(())
+    }
+
+    /// Get an upvalue for the variable in the given stack slot, reusing the existing one if
+    /// another closure has already captured it
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let position = self.open_upvalues.binary_search_by_key(&slot, open_slot);
+
+        match position {
+            Ok(existing) => self.open_upvalues[existing],
+            Err(insert_at) => {
+                let upvalue = self
+                    .collecting_heap()
+                    .allocate(ObjKind::Upvalue(ObjUpvalue {
+                        location: RefCell::new(UpvalueLocation::Open(slot)),
+                    }));
+                self.open_upvalues.insert(insert_at, upvalue);
+                upvalue
+            }
+        }
+    }
+
+    /// Close every open upvalue pointing at `last_slot` or above, copying the variable off the
+    /// stack into the upvalue itself
+    fn close_upvalues(&mut self, last_slot: usize) {
+        let first_closed = self
+            .open_upvalues
+            .partition_point(|upvalue| open_slot(upvalue) < last_slot);
+
+        for upvalue in self.open_upvalues.drain(first_closed..) {
+            let mut location = upvalue_of(&upvalue).location.borrow_mut();
+            if let UpvalueLocation::Open(slot) = *location {
+                let value = self.stack.get(slot).copied().unwrap_or(Value::Nil);
+                *location = UpvalueLocation::Closed(value);
+            }
+        }
+    }
+
+    /// The heap, after running the garbage collector if it's due. Anything which might allocate
+    /// should go through this, and every object the VM still needs has to be reachable from the
+    /// stack, a call frame, a global or an open upvalue when it does.
+    fn collecting_heap(&mut self) -> &mut Heap {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        &mut self.heap
+    }
+
+    fn collect_garbage(&mut self) {
+        let roots = VmRoots {
+            stack: &self.stack,
+            frames: &self.frames,
+            globals: &self.globals,
+            open_upvalues: &self.open_upvalues,
+            pinned: &self.pinned.borrow(),
+            init_string: self.init_string,
+        };
+        roots.mark_roots(&mut self.heap);
+        self.heap.collect();
+    }
+
+    // `run` only executes while there's a frame on the stack, so the opcodes can rely on there
+    // being a current frame
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("no active call frame")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("no active call frame")
+    }
+
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn pop(&mut self) -> Result<Value, RuntimeError> {
+        match self.stack.pop() {
+            Some(value) => Ok(value),
+            None => Err(self.runtime_error("Stack underflow.")),
+        }
+    }
+
+    /// Pop the superclass which the compiler put on the stack for a `super` expression
+    fn pop_class(&mut self) -> Result<ObjRef, RuntimeError> {
+        match self.pop()? {
+            Value::Obj(obj) if obj.as_class().is_some() => Ok(obj),
+            _ => Err(self.runtime_error("Expected a class.")),
+        }
+    }
+
+    /// Look at the value `distance` slots down from the top of the stack without popping it
+    fn peek(&self, distance: usize) -> Result<Value, RuntimeError> {
+        self.stack
+            .len()
+            .checked_sub(distance + 1)
+            .and_then(|index| self.stack.get(index))
+            .copied()
+            .ok_or_else(|| self.runtime_error("Stack underflow."))
+    }
+
+    /// Read a constant which the compiler put there as the name of a variable
+    fn read_string(&self, chunk: &Chunk, index: usize) -> Result<ObjRef, RuntimeError> {
+        match chunk.get_constant(index) {
+            Value::Obj(obj) if obj.as_string().is_some() => Ok(obj),
+            _ => Err(self.runtime_error("Expected a variable name.")),
+        }
+    }
+
+    fn undefined_variable(&self, name: ObjRef) -> RuntimeError {
+        self.runtime_error(&format!("Undefined variable '{}'.", name))
+    }
+
+    fn undefined_property(&self, name: ObjRef) -> RuntimeError {
+        self.runtime_error(&format!("Undefined property '{}'.", name))
+    }
+
+    fn reset_stack(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.open_upvalues.clear();
     }
 
     fn print_stack(&self) {
-        println!("Stack: {:?}", self.stack.borrow());
+        println!("Stack: {:?}", self.stack);
+    }
+}
+
+// The helpers below unwrap objects whose type the VM has already made sure of, e.g. only closures
+// ever end up in a `CallFrame` because `call_value` checks for them.
+
+fn closure_of(closure: &ObjRef) -> &ObjClosure {
+    closure
+        .as_closure()
+        .expect("call frames only ever hold closures")
+}
+
+/// The function wrapped by a closure
+fn function_of(closure: &ObjRef) -> &ObjFunction {
+    function_of_obj(&closure_of(closure).function)
+}
+
+fn function_of_obj(function: &ObjRef) -> &ObjFunction {
+    function
+        .as_function()
+        .expect("closures only ever wrap functions")
+}
+
+fn class_of(class: &ObjRef) -> &ObjClass {
+    class
+        .as_class()
+        .expect("instances only ever belong to classes")
+}
+
+fn instance_of(instance: &ObjRef) -> &ObjInstance {
+    instance
+        .as_instance()
+        .expect("only instances have properties")
+}
+
+fn upvalue_of(upvalue: &ObjRef) -> &ObjUpvalue {
+    upvalue
+        .as_upvalue()
+        .expect("closures only ever capture upvalues")
+}
+
+/// The stack slot an open upvalue points at
+fn open_slot(upvalue: &ObjRef) -> usize {
+    match *upvalue_of(upvalue).location.borrow() {
+        UpvalueLocation::Open(slot) => slot,
+        UpvalueLocation::Closed(_) => usize::MAX,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expect_runtime_error(vm: &mut VM, source: &str, message: &str, line: i32) {
+        match vm.interpret(source) {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.message, message);
+                assert_eq!(error.span.line, line);
+            }
+            _ => panic!("expected a runtime error for {:?}", source),
+        }
+    }
+
+    fn trace_lines(error: &RuntimeError) -> Vec<String> {
+        error.trace.iter().map(|frame| frame.to_string()).collect()
+    }
+
+    #[test]
+    fn type_errors_are_runtime_errors() {
+        let mut vm = VM::init();
+        expect_runtime_error(&mut vm, "-true;", "Operand must be a number.", 1);
+        expect_runtime_error(&mut vm, "1 - nil;", "Operands must be numbers.", 1);
+        // binary operators report errors at the operator, rather than at an operand
+        expect_runtime_error(&mut vm, "\n\n1 <\n false;", "Operands must be numbers.", 3);
+    }
+
+    #[test]
+    fn comments_are_ignored() {
+        let mut vm = VM::init();
+        let source = "/// Doubles a number.\nfun double(n) { return n /* times */ * 2; }\n/*\n/* */\n*/ double(21);";
+        assert_eq!(vm.interpret(source).ok(), Some(Value::Number(42.0)));
+        // and lines inside block comments are still counted
+        expect_runtime_error(&mut vm, "/*\n\n*/ -nil;", "Operand must be a number.", 3);
+    }
+
+    #[test]
+    fn strings_concatenate() {
+        let mut vm = VM::init();
+        assert_eq!(
+            vm.interpret("\"foo\" + \"bar\" == \"foobar\";").ok(),
+            Some(Value::Bool(true))
+        );
+        expect_runtime_error(
+            &mut vm,
+            "\"foo\" + 1;",
+            "Operands must be two numbers or two strings.",
+            1,
+        );
+    }
+
+    #[test]
+    fn strings_interpolate() {
+        let mut vm = VM::init();
+        let source = r#"
+            class Point {}
+            fun greet(name) { return "Hi ${name}"; }
+            var n = 3;
+            "${n} ${nil} ${true} ${n / 2} ${Point} ${Point()} ${greet} ${"<${greet("you")}>"}";
+        "#;
+
+        let value = vm.interpret(source).unwrap();
+        assert_eq!(
+            value.as_string(),
+            Some("3 nil true 1.5 Point Point instance <fn greet> <Hi you>")
+        );
+        assert_eq!(
+            vm.interpret(r#""tab\there \"quoted\" \${not} \u{2713}";"#)
+                .unwrap()
+                .as_string(),
+            Some("tab\there \"quoted\" ${not} ✓")
+        );
+    }
+
+    #[test]
+    fn interned_strings_survive_between_runs() {
+        let mut vm = VM::init();
+        // the intern table doesn't keep strings alive by itself, so hold onto it with a global
+        vm.interpret("var foobar = \"foo\" + \"bar\";").unwrap();
+        let foobar = vm.get_global("foobar");
+
+        vm.interpret("var same = \"foobar\";").unwrap();
+        assert!(vm.get_global("same") == foobar);
+    }
+
+    #[test]
+    fn globals_persist_between_runs() {
+        let mut vm = VM::init();
+        assert!(vm.interpret("var x = 1;").is_ok());
+        assert!(vm.interpret("var y;").is_ok());
+        assert!(vm.interpret("x = x + 2;").is_ok());
+
+        assert_eq!(vm.get_global("x"), Some(Value::Number(3.0)));
+        assert_eq!(vm.get_global("y"), Some(Value::Nil));
+    }
+
+    #[test]
+    fn local_variables() {
+        let mut vm = VM::init();
+        let source = "
+            var result;
+            {
+                var a = 1;
+                {
+                    var b = a + 1;
+                    a = b * 10;
+                }
+                result = a;
+            }
+        ";
+        assert!(vm.interpret(source).is_ok());
+        assert_eq!(vm.get_global("result"), Some(Value::Number(20.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn control_flow() {
+        let mut vm = VM::init();
+        let source = "
+            var evens = 0;
+            var odds = 0;
+            for (var i = 0; i < 10; i = i + 1) {
+                var half = i / 2;
+                var rounded = 0;
+                while (rounded + 1 <= half) rounded = rounded + 1;
+
+                if (rounded == half) evens = evens + 1; else odds = odds + 1;
+            }
+        ";
+        assert!(vm.interpret(source).is_ok());
+        assert_eq!(vm.get_global("evens"), Some(Value::Number(5.0)));
+        assert_eq!(vm.get_global("odds"), Some(Value::Number(5.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn logical_operators_short_circuit() {
+        let mut vm = VM::init();
+        let source = "
+            var a = nil and undefined;
+            var b = 1 or undefined;
+            var c = false or \"c\";
+            var d = true and 2;
+        ";
+        assert!(vm.interpret(source).is_ok());
+        assert_eq!(vm.get_global("a"), Some(Value::Nil));
+        assert_eq!(vm.get_global("b"), Some(Value::Number(1.0)));
+        assert_eq!(vm.get_global("d"), Some(Value::Number(2.0)));
+        assert_eq!(
+            vm.get_global("c")
+                .and_then(|c| c.as_string().map(String::from)),
+            Some(String::from("c"))
+        );
+    }
+
+    #[test]
+    fn functions() {
+        let mut vm = VM::init();
+        let source = "
+            fun fib(n) {
+                if (n < 2) return n;
+                return fib(n - 2) + fib(n - 1);
+            }
+            fun nothing() {}
+
+            var result = fib(10);
+            var empty = nothing();
+        ";
+        assert!(vm.interpret(source).is_ok());
+        assert_eq!(vm.get_global("result"), Some(Value::Number(55.0)));
+        assert_eq!(vm.get_global("empty"), Some(Value::Nil));
+        assert!(vm.stack.is_empty());
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn call_errors() {
+        let mut vm = VM::init();
+        vm.interpret("fun pair(a, b) { return a; }").unwrap();
+
+        expect_runtime_error(&mut vm, "pair(1);", "Expected 2 arguments but got 1.", 1);
+        expect_runtime_error(
+            &mut vm,
+            "\"pair\"();",
+            "Can only call functions and classes.",
+            1,
+        );
+        expect_runtime_error(&mut vm, "nil();", "Can only call functions and classes.", 1);
+    }
+
+    #[test]
+    fn classes_and_instances() {
+        let mut vm = VM::init();
+        let source = "
+            class Pair {}
+            var pair = Pair();
+            pair.first = 1;
+            pair.second = 2;
+            var sum = pair.first + pair.second;
+            var chained = pair.first = 3;
+        ";
+        assert!(vm.interpret(source).is_ok());
+        assert_eq!(vm.get_global("sum"), Some(Value::Number(3.0)));
+        assert_eq!(vm.get_global("chained"), Some(Value::Number(3.0)));
+        assert_eq!(
+            vm.get_global("pair").map(|pair| pair.to_string()),
+            Some(String::from("Pair instance"))
+        );
+    }
+
+    #[test]
+    fn methods_and_initializers() {
+        let mut vm = VM::init();
+        let source = "
+            class Counter {
+                init(start) {
+                    this.count = start;
+                }
+                increment() {
+                    this.count = this.count + 1;
+                    return this;
+                }
+            }
+            var counter = Counter(10);
+            var result = counter.increment().increment().count;
+
+            // a method keeps hold of its instance after being looked up
+            var increment = counter.increment;
+            increment();
+            var afterBound = counter.count;
+
+            // calling `init` directly hands back the instance, even with an early return
+            class Early { init() { this.x = 1; return; this.x = 2; } }
+            var early = Early();
+            var reinit = early.init();
+            var same = reinit == early;
+        ";
+        assert!(vm.interpret(source).is_ok());
+        assert_eq!(vm.get_global("result"), Some(Value::Number(12.0)));
+        assert_eq!(vm.get_global("afterBound"), Some(Value::Number(13.0)));
+        assert_eq!(vm.get_global("same"), Some(Value::Bool(true)));
+    }
+
+    #[test]
+    fn fields_shadow_methods() {
+        let mut vm = VM::init();
+        let source = "
+            class Box {
+                get() { return \"method\"; }
+            }
+            fun field() { return \"field\"; }
+            var box = Box();
+            box.get = field;
+            var result = box.get();
+        ";
+        assert!(vm.interpret(source).is_ok());
+        assert_eq!(
+            vm.get_global("result")
+                .and_then(|v| v.as_string().map(String::from)),
+            Some(String::from("field"))
+        );
+    }
+
+    #[test]
+    fn class_errors() {
+        let mut vm = VM::init();
+        vm.interpret("class Empty {} class One { init(a) {} } var empty = Empty();")
+            .unwrap();
+
+        expect_runtime_error(&mut vm, "Empty(1);", "Expected 0 arguments but got 1.", 1);
+        expect_runtime_error(&mut vm, "One();", "Expected 1 arguments but got 0.", 1);
+        expect_runtime_error(&mut vm, "empty.x;", "Undefined property 'x'.", 1);
+        expect_runtime_error(&mut vm, "empty.x();", "Undefined property 'x'.", 1);
+        expect_runtime_error(&mut vm, "nil.x;", "Only instances have properties.", 1);
+        expect_runtime_error(&mut vm, "1.x;", "Only instances have properties.", 1);
+        expect_runtime_error(&mut vm, "Empty.x = 1;", "Only instances have fields.", 1);
+        expect_runtime_error(&mut vm, "\"s\".len();", "Only instances have methods.", 1);
+    }
+
+    #[test]
+    fn inheritance() {
+        let mut vm = VM::init();
+        let source = "
+            class Animal {
+                init(name) { this.name = name; }
+                speak() { return this.name + \" makes a sound\"; }
+                kind() { return \"animal\"; }
+            }
+            class Dog < Animal {
+                init(name) { super.init(name + \" the dog\"); }
+                speak() { return super.speak() + \": woof\"; }
+            }
+            class Puppy < Dog {
+                speak() {
+                    var method = super.speak;
+                    return method() + \"!\";
+                }
+            }
+            var dog = Dog(\"Rex\").speak();
+            var puppy = Puppy(\"Bit\").speak();
+            var inherited = Puppy(\"Bit\").kind();
+        ";
+        assert!(vm.interpret(source).is_ok());
+
+        let as_string = |name: &str| {
+            vm.get_global(name)
+                .and_then(|v| v.as_string().map(String::from))
+        };
+        assert_eq!(
+            as_string("dog"),
+            Some(String::from("Rex the dog makes a sound: woof"))
+        );
+        assert_eq!(
+            as_string("puppy"),
+            Some(String::from("Bit the dog makes a sound: woof!"))
+        );
+        assert_eq!(as_string("inherited"), Some(String::from("animal")));
+    }
+
+    #[test]
+    fn inheritance_errors() {
+        let mut vm = VM::init();
+        expect_runtime_error(
+            &mut vm,
+            "var NotAClass = 1; class A < NotAClass {}",
+            "Superclass must be a class.",
+            1,
+        );
+        expect_runtime_error(
+            &mut vm,
+            "class A {} class B < A { f() { return super.missing(); } } B().f();",
+            "Undefined property 'missing'.",
+            1,
+        );
+    }
+
+    fn add(_context: &mut NativeContext, args: &[LoxValue]) -> Result<LoxValue, String> {
+        match (&args[0], &args[1]) {
+            (LoxValue::Number(a), LoxValue::Number(b)) => Ok(LoxValue::Number(a + b)),
+            _ => Ok(LoxValue::Nil),
+        }
+    }
+
+    fn fail(_context: &mut NativeContext, args: &[LoxValue]) -> Result<LoxValue, String> {
+        Err(format!("Failed with {}.", args[0]))
+    }
+
+    fn identity(_context: &mut NativeContext, args: &[LoxValue]) -> Result<LoxValue, String> {
+        Ok(args[0].clone())
+    }
+
+    #[test]
+    fn natives_pass_objects_back_and_forth() {
+        let mut vm = VM::init();
+        vm.define_native("identity", 1, Box::new(identity));
+
+        let source = "
+            class A {}
+            var a = A();
+            var same = identity(a) == a;
+            var string = identity(\"a\" + \"b\") == \"ab\";
+        ";
+        assert!(vm.interpret(source).is_ok());
+        assert_eq!(vm.get_global("same"), Some(Value::Bool(true)));
+        // strings come back interned, so they're still equal to the same string from Lox
+        assert_eq!(vm.get_global("string"), Some(Value::Bool(true)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn native_functions() {
+        let mut vm = VM::init();
+        vm.define_native("add", 2, Box::new(add));
+        vm.define_native("fail", 1, Box::new(fail));
+
+        let source = "
+            var sum = add(1, add(2, 3));
+            var now = clock();
+            var native = add;
+        ";
+        assert!(vm.interpret(source).is_ok());
+        assert_eq!(vm.get_global("sum"), Some(Value::Number(6.0)));
+        assert!(matches!(vm.get_global("now"), Some(Value::Number(_))));
+        assert_eq!(
+            vm.get_global("native").map(|native| native.to_string()),
+            Some(String::from("<native fn>"))
+        );
+        // the arguments and the native itself are gone from the stack afterwards
+        assert!(vm.stack.is_empty());
+
+        expect_runtime_error(&mut vm, "add(1);", "Expected 2 arguments but got 1.", 1);
+        expect_runtime_error(&mut vm, "\n\nfail(42);", "Failed with 42.", 3);
+
+        // errors raised by natives point at the Lox code that called them
+        match vm.interpret("fun f() {\n  fail(nil);\n}\nf();") {
+            Err(LoxError::Runtime(error)) => {
+                assert_eq!(error.span.line, 2);
+                assert_eq!(
+                    trace_lines(&error),
+                    vec!["[line 2] in f()", "[line 4] in script"]
+                );
+            }
+            _ => panic!("expected a runtime error"),
+        }
+    }
+
+    #[test]
+    fn runtime_errors_have_a_stack_trace() {
+        let mut vm = VM::init();
+        let source = "fun inner() {\n  return -nil;\n}\nfun outer() {\n  inner();\n}\n\nouter();";
+
+        match vm.interpret(source) {
+            Err(LoxError::Runtime(error)) => {
+                // the error points at the operand which isn't a number
+                assert_eq!(error.span.line, 2);
+                assert_eq!(error.span.column, 11);
+                assert_eq!(
+                    error.span.underline(source),
+                    Some(String::from("  return -nil;\n          ^^^"))
+                );
+                assert_eq!(
+                    trace_lines(&error),
+                    vec![
+                        "[line 2] in inner()",
+                        "[line 5] in outer()",
+                        "[line 8] in script"
+                    ]
+                );
+            }
+            _ => panic!("expected a runtime error"),
+        }
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let mut vm = VM::init();
+        expect_runtime_error(
+            &mut vm,
+            "fun forever() { forever(); } forever();",
+            "Stack overflow.",
+            1,
+        );
+
+        vm.set_max_frames(3);
+        vm.interpret("fun depth(n) { if (n > 0) depth(n - 1); }")
+            .unwrap();
+        assert!(vm.interpret("depth(1);").is_ok());
+        expect_runtime_error(&mut vm, "depth(2);", "Stack overflow.", 1);
+    }
+
+    #[test]
+    fn counter_closure() {
+        let mut vm = VM::init();
+        let source = "
+            fun makeCounter() {
+                var count = 0;
+                fun increment() {
+                    count = count + 1;
+                    return count;
+                }
+                return increment;
+            }
+
+            var first = makeCounter();
+            var second = makeCounter();
+            first();
+            first();
+            var a = first();
+            var b = second();
+        ";
+        assert!(vm.interpret(source).is_ok());
+        assert_eq!(vm.get_global("a"), Some(Value::Number(3.0)));
+        assert_eq!(vm.get_global("b"), Some(Value::Number(1.0)));
+        assert!(vm.open_upvalues.is_empty());
+    }
+
+    #[test]
+    fn closures_share_captured_variables() {
+        let mut vm = VM::init();
+        let source = "
+            var get;
+            var set;
+            {
+                var shared = \"before\";
+                fun getter() { return shared; }
+                fun setter(value) { shared = value; }
+                get = getter;
+                set = setter;
+
+                set(\"during\");
+                shared = shared + \"!\";
+            }
+            var during = get();
+            set(\"after\");
+            var after = get();
+        ";
+        assert!(vm.interpret(source).is_ok());
+
+        let as_string = |value: Option<Value>| value.and_then(|v| v.as_string().map(String::from));
+        assert_eq!(
+            as_string(vm.get_global("during")),
+            Some(String::from("during!"))
+        );
+        assert_eq!(
+            as_string(vm.get_global("after")),
+            Some(String::from("after"))
+        );
+    }
+
+    #[test]
+    fn closures_in_a_loop() {
+        let mut vm = VM::init();
+        let source = "
+            var loopVar;
+            var bodyVar;
+            for (var i = 0; i < 3; i = i + 1) {
+                var j = i;
+                fun getI() { return i; }
+                fun getJ() { return j; }
+                if (j == 1) {
+                    loopVar = getI;
+                    bodyVar = getJ;
+                }
+            }
+            var i = loopVar();
+            var j = bodyVar();
+        ";
+        assert!(vm.interpret(source).is_ok());
+        // there's only one `i` for the whole loop, so the closure sees its final value, but each
+        // pass through the body gets a fresh `j`
+        assert_eq!(vm.get_global("i"), Some(Value::Number(3.0)));
+        assert_eq!(vm.get_global("j"), Some(Value::Number(1.0)));
+    }
+
+    #[test]
+    fn nested_closures() {
+        let mut vm = VM::init();
+        let source = "
+            fun outer() {
+                var x = \"outside\";
+                fun middle() {
+                    fun inner() { return x; }
+                    return inner;
+                }
+                return middle;
+            }
+            var result = outer()()();
+        ";
+        assert!(vm.interpret(source).is_ok());
+        assert_eq!(
+            vm.get_global("result")
+                .and_then(|v| v.as_string().map(String::from)),
+            Some(String::from("outside"))
+        );
+    }
+
+    #[test]
+    fn garbage_is_collected() {
+        let mut vm = VM::init();
+        let source = "
+            class Node {}
+            var kept = Node();
+            kept.name = \"kept\";
+            fun makeClosure(value) {
+                fun closure() { return value; }
+                return closure;
+            }
+            var closure = makeClosure(\"captured\");
+
+            // enough garbage to go over the first collection threshold a few times
+            for (var i = 0; i < 50000; i = i + 1) {
+                var node = Node();
+                node.next = Node();
+                node.name = \"garbage\" + \"!\";
+            }
+            var result = closure();
+        ";
+        assert!(vm.interpret(source).is_ok());
+        // without collecting, the loop would have left well over 10MB of garbage behind
+        assert!(vm.heap.bytes_allocated() < 2 * 1024 * 1024);
+
+        // collecting again from the top level only leaves what the globals still use
+        vm.collect_garbage();
+        assert_eq!(
+            vm.get_global("kept").map(|kept| kept.to_string()),
+            Some(String::from("Node instance"))
+        );
+        assert_eq!(
+            vm.get_global("result")
+                .and_then(|v| v.as_string().map(String::from)),
+            Some(String::from("captured"))
+        );
+        assert!(vm.heap.object_count() < 30);
+    }
+
+    #[test]
+    fn undefined_globals_are_runtime_errors() {
+        let mut vm = VM::init();
+        expect_runtime_error(&mut vm, "1 + foo;", "Undefined variable 'foo'.", 1);
+        expect_runtime_error(&mut vm, "\nbar = 1;", "Undefined variable 'bar'.", 2);
+
+        // a failed assignment must not define the variable
+        assert_eq!(vm.get_global("bar"), None);
+    }
+
+    #[test]
+    fn only_a_top_level_expression_statement_is_returned() {
+        let mut vm = VM::init();
+        // loop and `if` bodies at the end of the script run as usual
+        assert_eq!(
+            vm.interpret("var i = 0; while (i < 3) i = i + 1;").ok(),
+            Some(Value::Nil)
+        );
+        assert_eq!(vm.get_global("i"), Some(Value::Number(3.0)));
+        assert_eq!(vm.interpret("if (true) 1;").ok(), Some(Value::Nil));
+        assert_eq!(
+            vm.interpret("for (var j = 0; j < 2; j = j + 1) j;").ok(),
+            Some(Value::Nil)
+        );
+        assert!(vm.stack.is_empty());
+
+        assert_eq!(vm.interpret("i;").ok(), Some(Value::Number(3.0)));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn stack_is_reset_after_runtime_error() {
+        let mut vm = VM::init();
+        vm.set_output(Box::new(io::sink()));
+        expect_runtime_error(&mut vm, "1 + (2 * -nil);", "Operand must be a number.", 1);
+        assert!(vm.stack.is_empty());
+
+        // the same VM keeps working afterwards
+        assert!(vm.interpret("print 1 + 2;").is_ok());
     }
 }